@@ -0,0 +1,342 @@
+// High-level lookups
+//
+// `recursive::resolve_question` speaks DNS: it takes a question made of labels and hands back a
+// whole packet. Most callers just want the addresses for a host, or the mail servers for a
+// domain, so these functions build the question from an ordinary name, resolve it, and pull the
+// records of the type asked for out of the answer (skipping over any CNAMEs on the way).
+//
+// A name that exists but has no records of the type asked for gives an empty list. A name that
+// doesn't exist at all is a LookupError::NoSuchName, and a server failure a LookupError::Failed,
+// so callers can tell the two apart from a resolution error by downcasting.
+
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+
+use rand::Rng;
+
+use super::protocol::{
+    parse_name, DnsClass, DnsPacket, DnsQuestion, DnsRCode, DnsRRType, DnsRecordData,
+};
+use super::recursive;
+
+#[derive(Debug, PartialEq)]
+pub enum LookupError {
+    // The name doesn't exist (NXDOMAIN)
+    NoSuchName(String),
+    // The lookup got an answer, but it was an error like SERVFAIL or REFUSED
+    Failed(String, DnsRCode),
+}
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LookupError::NoSuchName(name) => write!(f, "{} doesn't exist", name),
+            LookupError::Failed(name, rcode) => write!(f, "Lookup of {} failed: {:?}", name, rcode),
+        }
+    }
+}
+
+impl Error for LookupError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mx {
+    // Lower is more preferred
+    pub preference: u16,
+    pub exchange: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+// Every IPv4 and IPv6 address for `name`, IPv4 first. It's only an error if both lookups fail.
+pub fn lookup_ip(name: &str) -> Result<Vec<IpAddr>, Box<dyn Error + Send + Sync>> {
+    let v4 = lookup(name, DnsRRType::A);
+    let v6 = lookup(name, DnsRRType::AAAA);
+    let (v4, v6) = match (v4, v6) {
+        (Err(error), Err(_)) => return Err(error),
+        (v4, v6) => (v4.unwrap_or_default(), v6.unwrap_or_default()),
+    };
+    Ok(v4
+        .iter()
+        .chain(v6.iter())
+        .filter_map(|record| match record {
+            DnsRecordData::A(address) => Some(IpAddr::V4(*address)),
+            DnsRecordData::AAAA(address) => Some(IpAddr::V6(*address)),
+            _ => None,
+        })
+        .collect())
+}
+
+// The mail servers for `name`, most preferred first
+pub fn lookup_mx(name: &str) -> Result<Vec<Mx>, Box<dyn Error + Send + Sync>> {
+    let mut exchanges: Vec<Mx> = lookup(name, DnsRRType::MX)?
+        .into_iter()
+        .filter_map(|record| match record {
+            DnsRecordData::MX {
+                preference,
+                exchange,
+            } => Some(Mx {
+                preference,
+                exchange: exchange.join("."),
+            }),
+            _ => None,
+        })
+        .collect();
+    exchanges.sort_by_key(|mx| mx.preference);
+    Ok(exchanges)
+}
+
+// The servers for a service, like "_sip._tcp.example.com", in the order to try them (see
+// `order_srv`). A single record with a target of "." means the service isn't available at this
+// domain, which gives an empty list.
+pub fn lookup_srv(name: &str) -> Result<Vec<Srv>, Box<dyn Error + Send + Sync>> {
+    let servers: Vec<Srv> = lookup(name, DnsRRType::SRV)?
+        .into_iter()
+        .filter_map(|record| match record {
+            DnsRecordData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => Some(Srv {
+                priority,
+                weight,
+                port,
+                target: target.join("."),
+            }),
+            _ => None,
+        })
+        .collect();
+    if servers.len() == 1 && servers[0].target.is_empty() {
+        return Ok(vec![]);
+    }
+    Ok(order_srv(servers, &mut rand::thread_rng()))
+}
+
+// Each of the TXT records for `name`. A record can be split into several strings (each is limited
+// to 255 bytes); they're joined back together here, and anything that isn't UTF-8 is replaced.
+pub fn lookup_txt(name: &str) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    Ok(lookup(name, DnsRRType::TXT)?
+        .into_iter()
+        .filter_map(|record| match record {
+            DnsRecordData::TXT(strings) => Some(String::from_utf8_lossy(&strings.concat()).into()),
+            _ => None,
+        })
+        .collect())
+}
+
+// The names for an address, from its PTR records
+pub fn reverse_lookup(address: IpAddr) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let name = reverse_name(address).join(".");
+    Ok(lookup(&name, DnsRRType::PTR)?
+        .into_iter()
+        .filter_map(|record| match record {
+            DnsRecordData::PTR(name) => Some(name.join(".")),
+            _ => None,
+        })
+        .collect())
+}
+
+// The name reverse lookups for `address` go to: the octets backwards under in-addr.arpa for IPv4
+// (RFC 1035 section 3.5), and the hex digits backwards under ip6.arpa for IPv6 (RFC 3596
+// section 2.5)
+pub fn reverse_name(address: IpAddr) -> Vec<String> {
+    let mut labels: Vec<String> = match address {
+        IpAddr::V4(address) => address
+            .octets()
+            .iter()
+            .rev()
+            .map(|octet| octet.to_string())
+            .collect(),
+        IpAddr::V6(address) => address
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|octet| vec![octet & 0xf, octet >> 4])
+            .map(|nibble| format!("{:x}", nibble))
+            .collect(),
+    };
+    let suffix = match address {
+        IpAddr::V4(_) => ["in-addr", "arpa"],
+        IpAddr::V6(_) => ["ip6", "arpa"],
+    };
+    labels.extend(suffix.iter().map(|label| label.to_string()));
+    labels
+}
+
+// Put SRV records in the order RFC 2782 says to try them: lowest priority first, and within each
+// priority, a weighted random order, so a server with twice the weight is picked first twice as
+// often. As the RFC lays it out, servers with a weight of zero go at the front and the pick is
+// from 0 to the total weight inclusive, so a zero-weight server still gets picked first
+// 1/(total + 1) of the time.
+pub fn order_srv(mut servers: Vec<Srv>, rng: &mut impl Rng) -> Vec<Srv> {
+    servers.sort_by_key(|srv| (srv.priority, srv.weight != 0));
+    let mut ordered = Vec::with_capacity(servers.len());
+    while !servers.is_empty() {
+        let priority = servers[0].priority;
+        let end = servers
+            .iter()
+            .position(|srv| srv.priority != priority)
+            .unwrap_or(servers.len());
+        let mut group: Vec<Srv> = servers.drain(..end).collect();
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|srv| u32::from(srv.weight)).sum();
+            let pick = rng.gen_range(0..=total);
+            let mut running = 0;
+            let chosen = group
+                .iter()
+                .position(|srv| {
+                    running += u32::from(srv.weight);
+                    running >= pick
+                })
+                .unwrap_or(0);
+            ordered.push(group.remove(chosen));
+        }
+    }
+    ordered
+}
+
+// Resolve `name` and return the data of the records of `qtype` in the answer
+fn lookup(
+    name: &str,
+    qtype: DnsRRType,
+) -> Result<Vec<DnsRecordData>, Box<dyn Error + Send + Sync>> {
+    let question = DnsQuestion {
+        qname: parse_name(name),
+        qtype,
+        qclass: DnsClass::IN,
+    };
+    let response = recursive::resolve_question(&question)?;
+    Ok(records_of_type(name, qtype, response)?)
+}
+
+fn records_of_type(
+    name: &str,
+    qtype: DnsRRType,
+    response: DnsPacket,
+) -> Result<Vec<DnsRecordData>, LookupError> {
+    match response.flags.rcode {
+        DnsRCode::NoError => (),
+        DnsRCode::NXDomain => return Err(LookupError::NoSuchName(name.to_owned())),
+        rcode => return Err(LookupError::Failed(name.to_owned(), rcode)),
+    }
+    Ok(response
+        .answers
+        .into_iter()
+        .filter(|rr| rr.rr_type == qtype)
+        .map(|rr| rr.record)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::protocol::*;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(
+            reverse_name(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))).join("."),
+            "1.2.0.192.in-addr.arpa"
+        );
+        assert_eq!(
+            reverse_name(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x1))).join("."),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+    }
+
+    fn srv(priority: u16, weight: u16, target: &str) -> Srv {
+        Srv {
+            priority,
+            weight,
+            port: 5060,
+            target: target.to_owned(),
+        }
+    }
+
+    #[test]
+    fn srv_records_are_ordered_by_priority_then_weight() {
+        let servers = vec![
+            srv(20, 0, "backup.example.com"),
+            srv(10, 10, "light.example.com"),
+            srv(10, 90, "heavy.example.com"),
+            srv(10, 0, "last-resort.example.com"),
+        ];
+        let mut rng = StdRng::seed_from_u64(2782);
+        let mut heavy_first = 0;
+        for _ in 0..1000 {
+            let ordered = order_srv(servers.to_owned(), &mut rng);
+            let targets: Vec<&str> = ordered.iter().map(|srv| srv.target.as_str()).collect();
+            // Lower priorities always come first
+            assert_eq!(targets[3], "backup.example.com");
+            if targets[0] == "heavy.example.com" {
+                heavy_first += 1;
+            }
+        }
+        // 90% of the time, give or take
+        assert!((850..950).contains(&heavy_first), "{}", heavy_first);
+    }
+
+    #[test]
+    fn answers_are_picked_out_of_the_response() {
+        let question = DnsQuestion {
+            qname: parse_name("www.example.com"),
+            qtype: DnsRRType::A,
+            qclass: DnsClass::IN,
+        };
+        let mut response = DnsPacket {
+            id: 0,
+            flags: DnsFlags {
+                qr_bit: true,
+                opcode: DnsOpcode::Query,
+                aa_bit: false,
+                tc_bit: false,
+                rd_bit: true,
+                ra_bit: true,
+                ad_bit: false,
+                cd_bit: false,
+                rcode: DnsRCode::NoError,
+            },
+            questions: vec![question],
+            answers: vec![
+                DnsResourceRecord {
+                    name: parse_name("www.example.com"),
+                    rr_type: DnsRRType::CNAME,
+                    class: DnsClass::IN,
+                    ttl: 300,
+                    record: DnsRecordData::CNAME(parse_name("example.com")),
+                },
+                DnsResourceRecord {
+                    name: parse_name("example.com"),
+                    rr_type: DnsRRType::A,
+                    class: DnsClass::IN,
+                    ttl: 300,
+                    record: DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+                },
+            ],
+            nameservers: vec![],
+            addl_recs: vec![],
+        };
+        assert_eq!(
+            records_of_type("www.example.com", DnsRRType::A, response.to_owned()),
+            Ok(vec![DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1))])
+        );
+
+        response.answers.clear();
+        response.flags.rcode = DnsRCode::NXDomain;
+        assert_eq!(
+            records_of_type("www.example.com", DnsRRType::A, response),
+            Err(LookupError::NoSuchName("www.example.com".to_owned()))
+        );
+    }
+}
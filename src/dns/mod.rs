@@ -1,2 +1,4 @@
+pub mod lookup;
 pub mod protocol;
 pub mod recursive;
+pub mod stub;
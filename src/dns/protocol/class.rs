@@ -1,5 +1,5 @@
 #[allow(dead_code)]
-#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum DnsClass {
     // 0: Reserved (RFC 6895)
     // 1: INternet - Basically the only actually used DNS Class
@@ -35,7 +35,7 @@ impl DnsClass {
         }
     }
 
-    pub fn to_u16(&self) -> u16 {
+    pub fn to_u16(self) -> u16 {
         match self {
             DnsClass::IN => 1,
             DnsClass::CS => 2,
@@ -44,7 +44,7 @@ impl DnsClass {
             DnsClass::NONE => 254,
             DnsClass::ANY => 255,
             // On an EDNS packet, the "class" is a payload size
-            DnsClass::EdnsPayloadSize(payload) => payload.to_owned(),
+            DnsClass::EdnsPayloadSize(payload) => payload,
         }
     }
 }
@@ -17,6 +17,7 @@ impl DnsFormatError {
         }
     }
 
+    #[allow(dead_code)]
     pub fn get_message(&self) -> &String {
         &self.message
     }
@@ -46,7 +46,7 @@ impl DnsFlags {
         let cd_bit: bool = (bytes[1] >> 4) & 1 == 1;
 
         if z_bit {
-            return Err(DnsFormatError::make_error(format!("Z bit was set")));
+            return Err(DnsFormatError::make_error("Z bit was set".to_owned()));
         }
 
         let opcode_val: u8 = (bytes[0] >> 3) & 0b1111;
@@ -18,6 +18,7 @@ mod rrtype;
 pub use class::DnsClass;
 pub use errors::DnsFormatError;
 pub use flags::DnsFlags;
+pub use names::{dname_substitute, lowercase_name, parse_name};
 pub use opcode::DnsOpcode;
 pub use packet::DnsPacket;
 pub use question::DnsQuestion;
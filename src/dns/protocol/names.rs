@@ -20,9 +20,10 @@ pub fn deserialize_name(
         // of the packet, but was not the root label (so we didn't return), and the case where a
         // pointer jumped us beyond the end of the packet
         if pos >= packet_len {
-            return Err(DnsFormatError::make_error(format!(
+            return Err(DnsFormatError::make_error(
                 "Reached end of packet while parsing label or label pointer jumped beyond packet"
-            )));
+                    .to_owned(),
+            ));
         }
         let len_byte = bytes[pos];
         // If the length begins with the bits 11, it is a pointer
@@ -33,9 +34,9 @@ pub fn deserialize_name(
                 // We're about to read two bytes, so we need to check that the next byte is also
                 // valid
                 if pos + 1 >= packet_len {
-                    return Err(DnsFormatError::make_error(format!(
-                        "Unexpected end of packet at label pointer start"
-                    )));
+                    return Err(DnsFormatError::make_error(
+                        "Unexpected end of packet at label pointer start".to_owned(),
+                    ));
                 }
                 // The pointer includes the lower 6 bits of the "length" and
                 // the entirety of the next byte
@@ -62,9 +63,9 @@ pub fn deserialize_name(
                 }
                 // Ensure the label we're about to read exists
                 if pos + length >= packet_len {
-                    return Err(DnsFormatError::make_error(format!(
-                        "Label length is longer than remainder of packet"
-                    )));
+                    return Err(DnsFormatError::make_error(
+                        "Label length is longer than remainder of packet".to_owned(),
+                    ));
                 }
                 // TODO the spec is kind of annoying here. It talks a lot about
                 // ASCII but doesn't ever require a domain is made of only ASCII
@@ -80,9 +81,9 @@ pub fn deserialize_name(
             _ => {
                 // Technically, there is another label type possible here, proposed in RFC6891.
                 // It's unclear if this is worth supporting in practice.
-                return Err(DnsFormatError::make_error(format!(
-                    "Unsupported or invalid label pointer type"
-                )));
+                return Err(DnsFormatError::make_error(
+                    "Unsupported or invalid label pointer type".to_owned(),
+                ));
             }
         }
     }
@@ -107,6 +108,54 @@ pub fn serialize_name(name: &Vec<String>) -> Vec<u8> {
     bytes
 }
 
+// Split a name written out the usual way, like "www.example.com" (with or without the trailing dot
+// for the root), into its labels
+pub fn parse_name(name: &str) -> Vec<String> {
+    name.split('.')
+        .filter(|label| !label.is_empty())
+        .map(|label| label.to_owned())
+        .collect()
+}
+
+// DNS names compare case-insensitively (RFC 4343), but only for ASCII letters; anything else in a
+// label has to match exactly. Lowercasing the ASCII letters gives us a canonical form to compare
+// or hash names by.
+pub fn lowercase_name(name: &[String]) -> Vec<String> {
+    name.iter()
+        .map(|label| label.to_ascii_lowercase())
+        .collect()
+}
+
+// DNAME substitution (RFC 6672 section 2.2): a DNAME at `owner` pointing to `target` maps every
+// name below `owner` to the same name below `target`, so "www.example.com" under a DNAME from
+// "example.com" to "example.net" becomes "www.example.net". Both resolvers (synthesizing a CNAME
+// from a DNAME in an answer) and authorities (answering for a name under a DNAME in their zone)
+// need this. Returns None if `name` isn't strictly below `owner`, or if the result is too long to
+// be a DNS name (which an authority reports as YXDOMAIN).
+pub fn dname_substitute(
+    name: &[String],
+    owner: &[String],
+    target: &[String],
+) -> Option<Vec<String>> {
+    if name.len() <= owner.len()
+        || lowercase_name(&name[name.len() - owner.len()..]) != lowercase_name(owner)
+    {
+        return None;
+    }
+    let mut substituted = name[..name.len() - owner.len()].to_vec();
+    substituted.extend_from_slice(target);
+    // Each label takes its length plus a length byte, and there's a final zero byte for the root
+    let wire_length: usize = substituted
+        .iter()
+        .map(|label| label.len() + 1)
+        .sum::<usize>()
+        + 1;
+    if wire_length > 255 {
+        return None;
+    }
+    Some(substituted)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::dns::protocol::names::*;
@@ -121,23 +170,23 @@ mod tests {
         let mut packet = [0x00u8; 93];
         // First label starting at byte 20 is f.isi.arpa
         packet[20] = 1;
-        packet[21] = 'f' as u8;
+        packet[21] = b'f';
         packet[22] = 3;
-        packet[23] = 'i' as u8;
-        packet[24] = 's' as u8;
-        packet[25] = 'i' as u8;
+        packet[23] = b'i';
+        packet[24] = b's';
+        packet[25] = b'i';
         packet[26] = 4;
-        packet[27] = 'a' as u8;
-        packet[28] = 'r' as u8;
-        packet[29] = 'p' as u8;
-        packet[30] = 'a' as u8;
+        packet[27] = b'a';
+        packet[28] = b'r';
+        packet[29] = b'p';
+        packet[30] = b'a';
         packet[31] = 0;
 
         // Second label starting at byte 40 is foo.f.isi.arpa
         packet[40] = 3;
-        packet[41] = 'f' as u8;
-        packet[42] = 'o' as u8;
-        packet[43] = 'o' as u8;
+        packet[41] = b'f';
+        packet[42] = b'o';
+        packet[43] = b'o';
         // Pointer to "f.isi.arpa" at byte 20
         packet[44] = 0b11000000;
         packet[45] = 20;
@@ -165,4 +214,42 @@ mod tests {
         assert_eq!(labels, Vec::<String>::new());
         assert_eq!(pos, 93);
     }
+
+    #[test]
+    fn dname_substitution() {
+        let labels =
+            |name: &str| -> Vec<String> { name.split('.').map(|label| label.to_owned()).collect() };
+        assert_eq!(
+            dname_substitute(
+                &labels("www.sub.Example.com"),
+                &labels("example.com"),
+                &labels("example.net")
+            ),
+            Some(labels("www.sub.example.net"))
+        );
+        // The DNAME's owner itself isn't redirected, and neither are names outside it
+        assert_eq!(
+            dname_substitute(
+                &labels("example.com"),
+                &labels("example.com"),
+                &labels("example.net")
+            ),
+            None
+        );
+        assert_eq!(
+            dname_substitute(
+                &labels("www.example.org"),
+                &labels("example.com"),
+                &labels("example.net")
+            ),
+            None
+        );
+        // Nor anything that would come out longer than 255 bytes
+        let long_label = "a".repeat(63);
+        let long_target = vec![long_label.to_owned(); 4];
+        assert_eq!(
+            dname_substitute(&labels("www.example.com"), &labels("com"), &long_target),
+            None
+        );
+    }
 }
@@ -19,12 +19,6 @@ pub struct DnsPacket {
 
 impl DnsPacket {
     pub fn from_bytes(bytes: &[u8]) -> Result<DnsPacket, DnsFormatError> {
-        let id: u16;
-        let flags: DnsFlags;
-        let qd_count: u16;
-        let an_count: u16;
-        let ns_count: u16;
-        let ar_count: u16;
         let mut questions: Vec<DnsQuestion> = Vec::new();
         let mut answers: Vec<DnsResourceRecord> = Vec::new();
         let mut nameservers: Vec<DnsResourceRecord> = Vec::new();
@@ -39,17 +33,17 @@ impl DnsPacket {
 
         // TODO(dylan): Error checking, e.g. DNS request too short
         // Read the first two bytes as a big-endian u16 containing transaction id
-        id = bigendians::to_u16(&bytes[0..2]);
+        let id = bigendians::to_u16(&bytes[0..2]);
         // Next two bytes are flags
         // If we get an error parsing the flags, we have too little info to
         // return a FormErr; we could just copy the bad flags but technically a
         // FormErr indicates an issue with the query, not the flags.
-        flags = DnsFlags::from_bytes(&bytes[2..4])?;
+        let flags = DnsFlags::from_bytes(&bytes[2..4])?;
         // Counts are next four u16s (big-endian)
-        qd_count = bigendians::to_u16(&bytes[4..6]);
-        an_count = bigendians::to_u16(&bytes[6..8]);
-        ns_count = bigendians::to_u16(&bytes[8..10]);
-        ar_count = bigendians::to_u16(&bytes[10..12]);
+        let qd_count = bigendians::to_u16(&bytes[4..6]);
+        let an_count = bigendians::to_u16(&bytes[6..8]);
+        let ns_count = bigendians::to_u16(&bytes[8..10]);
+        let ar_count = bigendians::to_u16(&bytes[10..12]);
 
         // The header was 12 bytes, we now begin reading the rest of the packet.
         // These components are variable length (thanks to how labels are
@@ -58,7 +52,7 @@ impl DnsPacket {
         for _ in 0..qd_count {
             // TODO(dylan): formerr logic is duplicated several times here,
             // might be helpful to turn it into a macro
-            match DnsQuestion::from_bytes(&bytes, pos) {
+            match DnsQuestion::from_bytes(bytes, pos) {
                 Ok((question, new_pos)) => {
                     pos = new_pos;
                     questions.push(question);
@@ -78,7 +72,7 @@ impl DnsPacket {
         }
 
         for _ in 0..an_count {
-            match DnsResourceRecord::from_bytes(&bytes, pos) {
+            match DnsResourceRecord::from_bytes(bytes, pos) {
                 Ok((rr, new_pos)) => {
                     pos = new_pos;
                     answers.push(rr);
@@ -98,7 +92,7 @@ impl DnsPacket {
         }
 
         for _ in 0..ns_count {
-            match DnsResourceRecord::from_bytes(&bytes, pos) {
+            match DnsResourceRecord::from_bytes(bytes, pos) {
                 Ok((rr, new_pos)) => {
                     pos = new_pos;
                     nameservers.push(rr);
@@ -118,7 +112,7 @@ impl DnsPacket {
         }
 
         for _ in 0..ar_count {
-            match DnsResourceRecord::from_bytes(&bytes, pos) {
+            match DnsResourceRecord::from_bytes(bytes, pos) {
                 Ok((rr, new_pos)) => {
                     pos = new_pos;
                     addl_recs.push(rr);
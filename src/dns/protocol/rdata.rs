@@ -8,6 +8,34 @@ pub enum DnsRecordData {
     NS(Vec<String>),
     AAAA(Ipv6Addr),
     CNAME(Vec<String>),
+    // Redirects every name below the owner to the same name below this target (RFC 6672)
+    DNAME(Vec<String>),
+    // Start of authority (RFC 1035 section 3.3.13). Besides marking the top of a zone, the SOA
+    // is what authorities attach to negative answers, and `minimum` doubles as the TTL for
+    // caching those (RFC 2308).
+    SOA {
+        mname: Vec<String>,
+        rname: Vec<String>,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    PTR(Vec<String>),
+    MX {
+        preference: u16,
+        exchange: Vec<String>,
+    },
+    // One or more character strings, each up to 255 bytes of whatever the owner wants
+    TXT(Vec<Vec<u8>>),
+    // Where to find a service (RFC 2782)
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: Vec<String>,
+    },
     Other(Vec<u8>),
 }
 
@@ -37,13 +65,80 @@ impl DnsRecordData {
                 bigendians::to_u16(&record_bytes[14..16]),
             )),
             DnsRRType::NS => {
-                let (name, _) = names::deserialize_name(&packet_bytes, pos)?;
+                let (name, _) = names::deserialize_name(packet_bytes, pos)?;
                 DnsRecordData::NS(name)
             }
             DnsRRType::CNAME => {
-                let (name, _) = names::deserialize_name(&packet_bytes, pos)?;
+                let (name, _) = names::deserialize_name(packet_bytes, pos)?;
                 DnsRecordData::CNAME(name)
             }
+            DnsRRType::DNAME => {
+                let (name, _) = names::deserialize_name(packet_bytes, pos)?;
+                DnsRecordData::DNAME(name)
+            }
+            DnsRRType::SOA => {
+                let (mname, name_end) = names::deserialize_name(packet_bytes, pos)?;
+                let (rname, name_end) = names::deserialize_name(packet_bytes, name_end)?;
+                if name_end + 20 > pos + rd_length as usize {
+                    return Err(DnsFormatError::make_error(
+                        "SOA record too short for its fields".to_owned(),
+                    ));
+                }
+                let fields = &packet_bytes[name_end..name_end + 20];
+                DnsRecordData::SOA {
+                    mname,
+                    rname,
+                    serial: bigendians::to_u32(&fields[0..4]),
+                    refresh: bigendians::to_u32(&fields[4..8]),
+                    retry: bigendians::to_u32(&fields[8..12]),
+                    expire: bigendians::to_u32(&fields[12..16]),
+                    minimum: bigendians::to_u32(&fields[16..20]),
+                }
+            }
+            DnsRRType::PTR => {
+                let (name, _) = names::deserialize_name(packet_bytes, pos)?;
+                DnsRecordData::PTR(name)
+            }
+            DnsRRType::MX => {
+                if record_bytes.len() < 2 {
+                    return Err(DnsFormatError::make_error(
+                        "MX record too short for its preference".to_owned(),
+                    ));
+                }
+                let (exchange, _) = names::deserialize_name(packet_bytes, pos + 2)?;
+                DnsRecordData::MX {
+                    preference: bigendians::to_u16(&record_bytes[0..2]),
+                    exchange,
+                }
+            }
+            DnsRRType::TXT => {
+                let mut strings = Vec::new();
+                let mut rest = &record_bytes[..];
+                while let Some((&length, string)) = rest.split_first() {
+                    if string.len() < length as usize {
+                        return Err(DnsFormatError::make_error(
+                            "TXT string runs past the end of its record".to_owned(),
+                        ));
+                    }
+                    strings.push(string[..length as usize].to_vec());
+                    rest = &string[length as usize..];
+                }
+                DnsRecordData::TXT(strings)
+            }
+            DnsRRType::SRV => {
+                if record_bytes.len() < 6 {
+                    return Err(DnsFormatError::make_error(
+                        "SRV record too short for its fields".to_owned(),
+                    ));
+                }
+                let (target, _) = names::deserialize_name(packet_bytes, pos + 6)?;
+                DnsRecordData::SRV {
+                    priority: bigendians::to_u16(&record_bytes[0..2]),
+                    weight: bigendians::to_u16(&record_bytes[2..4]),
+                    port: bigendians::to_u16(&record_bytes[4..6]),
+                    target,
+                }
+            }
             _ => DnsRecordData::Other(record_bytes),
         };
         pos += rd_length as usize;
@@ -55,9 +150,134 @@ impl DnsRecordData {
         match &self {
             DnsRecordData::A(ipv4) => ipv4.octets().to_vec(),
             DnsRecordData::AAAA(ipv6) => ipv6.octets().to_vec(),
-            DnsRecordData::NS(labels) => names::serialize_name(&labels),
-            DnsRecordData::CNAME(labels) => names::serialize_name(&labels),
+            DnsRecordData::NS(labels) => names::serialize_name(labels),
+            DnsRecordData::CNAME(labels) => names::serialize_name(labels),
+            DnsRecordData::DNAME(labels) => names::serialize_name(labels),
+            DnsRecordData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                let mut bytes = names::serialize_name(mname);
+                bytes.append(&mut names::serialize_name(rname));
+                for field in &[serial, refresh, retry, expire, minimum] {
+                    bytes.extend_from_slice(&bigendians::from_u32(**field));
+                }
+                bytes
+            }
+            DnsRecordData::PTR(labels) => names::serialize_name(labels),
+            DnsRecordData::MX {
+                preference,
+                exchange,
+            } => {
+                let mut bytes = bigendians::from_u16(*preference).to_vec();
+                bytes.append(&mut names::serialize_name(exchange));
+                bytes
+            }
+            DnsRecordData::TXT(strings) => {
+                let mut bytes = Vec::new();
+                for string in strings {
+                    bytes.push(string.len() as u8);
+                    bytes.extend_from_slice(string);
+                }
+                bytes
+            }
+            DnsRecordData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                let mut bytes = Vec::new();
+                for field in &[priority, weight, port] {
+                    bytes.extend_from_slice(&bigendians::from_u16(**field));
+                }
+                bytes.append(&mut names::serialize_name(target));
+                bytes
+            }
             DnsRecordData::Other(record_bytes) => record_bytes.to_vec(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn soa_round_trips() {
+        let soa = DnsRecordData::SOA {
+            mname: vec!["ns1".to_owned(), "example".to_owned(), "com".to_owned()],
+            rname: vec![
+                "hostmaster".to_owned(),
+                "example".to_owned(),
+                "com".to_owned(),
+            ],
+            serial: 2019101801,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+        };
+        let bytes = soa.to_bytes();
+        let (parsed, pos) =
+            DnsRecordData::from_bytes(&bytes, 0, &DnsRRType::SOA, bytes.len() as u16)
+                .expect("SOA should parse");
+        assert_eq!(parsed, soa);
+        assert_eq!(pos, bytes.len());
+    }
+
+    #[test]
+    fn dname_round_trips() {
+        let dname = DnsRecordData::DNAME(vec!["example".to_owned(), "net".to_owned()]);
+        let bytes = dname.to_bytes();
+        let (parsed, pos) =
+            DnsRecordData::from_bytes(&bytes, 0, &DnsRRType::DNAME, bytes.len() as u16)
+                .expect("DNAME should parse");
+        assert_eq!(parsed, dname);
+        assert_eq!(pos, bytes.len());
+    }
+
+    #[test]
+    fn mx_txt_srv_and_ptr_round_trip() {
+        let name = vec!["mail".to_owned(), "example".to_owned(), "com".to_owned()];
+        let records = vec![
+            (DnsRRType::PTR, DnsRecordData::PTR(name.to_owned())),
+            (
+                DnsRRType::MX,
+                DnsRecordData::MX {
+                    preference: 10,
+                    exchange: name.to_owned(),
+                },
+            ),
+            (
+                DnsRRType::TXT,
+                DnsRecordData::TXT(vec![b"v=spf1 -all".to_vec(), vec![], vec![0xff; 255]]),
+            ),
+            (
+                DnsRRType::SRV,
+                DnsRecordData::SRV {
+                    priority: 10,
+                    weight: 60,
+                    port: 5060,
+                    target: name.to_owned(),
+                },
+            ),
+        ];
+        for (rr_type, record) in records {
+            let bytes = record.to_bytes();
+            let (parsed, pos) = DnsRecordData::from_bytes(&bytes, 0, &rr_type, bytes.len() as u16)
+                .expect("record should parse");
+            assert_eq!(parsed, record);
+            assert_eq!(pos, bytes.len());
+        }
+
+        // A string claiming to be longer than what's left
+        let bytes = vec![5, b'a', b'b'];
+        assert!(DnsRecordData::from_bytes(&bytes, 0, &DnsRRType::TXT, 3).is_err());
+    }
+}
@@ -21,11 +21,11 @@ impl DnsResourceRecord {
         packet_bytes: &[u8],
         mut pos: usize,
     ) -> Result<(DnsResourceRecord, usize), DnsFormatError> {
-        let (name, new_pos) = names::deserialize_name(&packet_bytes, pos)?;
+        let (name, new_pos) = names::deserialize_name(packet_bytes, pos)?;
         if new_pos + 10 > packet_bytes.len() {
-            return Err(DnsFormatError::make_error(format!(
-                "End of packet parsing resource record"
-            )));
+            return Err(DnsFormatError::make_error(
+                "End of packet parsing resource record".to_owned(),
+            ));
         }
         let rrtype_num = bigendians::to_u16(&packet_bytes[new_pos..new_pos + 2]);
         let class_num = bigendians::to_u16(&packet_bytes[new_pos + 2..new_pos + 4]);
@@ -74,7 +74,7 @@ impl DnsResourceRecord {
         let record = &self.record.to_bytes();
 
         // Bounds check that the record isn't too large to fit in a u16.
-        let record_length = if record.len() <= std::u16::MAX as usize {
+        let record_length = if record.len() <= u16::MAX as usize {
             record.len() as u16
         } else {
             // There's not a way for our server to _receive_ a record this large, but this isn't
@@ -92,7 +92,7 @@ impl DnsResourceRecord {
         bytes.extend_from_slice(&bigendians::from_u16(self.class.to_u16()));
         bytes.extend_from_slice(&bigendians::from_u32(self.ttl));
         bytes.extend_from_slice(&bigendians::from_u16(record_length));
-        bytes.extend_from_slice(&record);
+        bytes.extend_from_slice(record);
         bytes
     }
 }
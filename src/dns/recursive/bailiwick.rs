@@ -0,0 +1,173 @@
+// Bailiwick checking
+//
+// A nameserver is only an authority for the zones it serves. When we ask the servers for
+// "example.com" about something, they get to tell us about names in example.com and nothing else.
+// Anything else they send (glue for a name in some other zone, an answer for "bank.com", a
+// referral back up to "com") is useless at best and an attempt to poison our cache at worst, so
+// every response from an authority goes through `scrub_response` before we look at it.
+
+use crate::dns::protocol::{lowercase_name, DnsPacket, DnsRRType, DnsResourceRecord};
+
+// Is `name` the same as `zone` or somewhere underneath it?
+pub fn in_bailiwick(name: &[String], zone: &[String]) -> bool {
+    name.len() >= zone.len()
+        && lowercase_name(&name[name.len() - zone.len()..]) == lowercase_name(zone)
+}
+
+// Remove everything from a response to a query sent to the servers for `zone` that those servers
+// have no business telling us about.
+pub fn scrub_response(response: &mut DnsPacket, zone: &[String]) {
+    let qname = match response.questions.first() {
+        Some(question) => question.qname.to_owned(),
+        None => return,
+    };
+    let before = response.answers.len() + response.nameservers.len() + response.addl_recs.len();
+
+    response.answers.retain(|rr| in_bailiwick(&rr.name, zone));
+    response
+        .nameservers
+        .retain(|rr| authority_record_allowed(rr, &qname, zone));
+    // The OPT pseudo-record isn't data about any name, so it always stays
+    response
+        .addl_recs
+        .retain(|rr| rr.rr_type == DnsRRType::OPT || in_bailiwick(&rr.name, zone));
+
+    let after = response.answers.len() + response.nameservers.len() + response.addl_recs.len();
+    if after < before {
+        log!(
+            "Dropped {} out-of-bailiwick records from response for zone {:?}",
+            before - after,
+            zone
+        );
+    }
+}
+
+fn authority_record_allowed(rr: &DnsResourceRecord, qname: &[String], zone: &[String]) -> bool {
+    if rr.rr_type == DnsRRType::NS {
+        // A referral has to point further down the tree, towards the name we asked about. NS
+        // records for the zone itself (which some servers include alongside answers) aren't
+        // useful to us either, and dropping them keeps an upward or sideways "referral" from
+        // sending us in circles.
+        rr.name.len() > zone.len() && in_bailiwick(&rr.name, zone) && in_bailiwick(qname, &rr.name)
+    } else {
+        in_bailiwick(&rr.name, zone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::protocol::*;
+
+    use std::net::Ipv4Addr;
+
+    fn record(name: &str, rr_type: DnsRRType, record: DnsRecordData) -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: parse_name(name),
+            rr_type,
+            class: DnsClass::IN,
+            ttl: 3600,
+            record,
+        }
+    }
+
+    fn a_record(name: &str) -> DnsResourceRecord {
+        record(
+            name,
+            DnsRRType::A,
+            DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 66)),
+        )
+    }
+
+    fn ns_record(zone: &str, ns: &str) -> DnsResourceRecord {
+        record(zone, DnsRRType::NS, DnsRecordData::NS(parse_name(ns)))
+    }
+
+    fn response(qname: &str) -> DnsPacket {
+        DnsPacket {
+            id: 1234,
+            flags: DnsFlags::from_bytes(&[0x80, 0x00]).unwrap(),
+            questions: vec![DnsQuestion {
+                qname: parse_name(qname),
+                qtype: DnsRRType::A,
+                qclass: DnsClass::IN,
+            }],
+            answers: vec![],
+            nameservers: vec![],
+            addl_recs: vec![],
+        }
+    }
+
+    #[test]
+    fn bailiwick_is_case_insensitive_suffix_match() {
+        assert!(in_bailiwick(
+            &parse_name("www.Example.com"),
+            &parse_name("example.COM")
+        ));
+        assert!(in_bailiwick(
+            &parse_name("example.com"),
+            &parse_name("example.com")
+        ));
+        assert!(in_bailiwick(&parse_name("example.com"), &parse_name("")));
+        assert!(!in_bailiwick(
+            &parse_name("example.com"),
+            &parse_name("www.example.com")
+        ));
+        assert!(!in_bailiwick(
+            &parse_name("badexample.com"),
+            &parse_name("example.com")
+        ));
+    }
+
+    #[test]
+    fn poisoned_glue_is_dropped() {
+        // The com servers refer us to example.com, and try to slip in addresses for a bank's
+        // website and for a nameserver in a zone they don't control
+        let mut packet = response("www.example.com");
+        packet.nameservers = vec![
+            ns_record("example.com", "ns1.example.com"),
+            ns_record("example.com", "ns.example.net"),
+        ];
+        packet.addl_recs = vec![
+            a_record("ns1.example.com"),
+            a_record("ns.example.net"),
+            a_record("www.bank.org"),
+        ];
+
+        scrub_response(&mut packet, &parse_name("com"));
+        assert_eq!(packet.nameservers.len(), 2);
+        assert_eq!(packet.addl_recs, vec![a_record("ns1.example.com")]);
+    }
+
+    #[test]
+    fn out_of_zone_answers_are_dropped() {
+        // The example.com servers answer with a CNAME out of their zone, and helpfully include
+        // an address for the target that they have no authority over
+        let mut packet = response("www.example.com");
+        let cname = record(
+            "www.example.com",
+            DnsRRType::CNAME,
+            DnsRecordData::CNAME(parse_name("www.bank.com")),
+        );
+        packet.answers = vec![cname.to_owned(), a_record("www.bank.com")];
+
+        scrub_response(&mut packet, &parse_name("example.com"));
+        assert_eq!(packet.answers, vec![cname]);
+    }
+
+    #[test]
+    fn upward_and_sideways_referrals_are_dropped() {
+        let mut packet = response("www.example.com");
+        packet.nameservers = vec![
+            // Back up to the root
+            ns_record("", "a.root-servers.net"),
+            // To the zone we already asked
+            ns_record("com", "a.gtld-servers.net"),
+            // To a zone that doesn't contain our question
+            ns_record("bank.com", "ns.attacker.net"),
+        ];
+
+        scrub_response(&mut packet, &parse_name("com"));
+        assert_eq!(packet.nameservers, vec![]);
+    }
+}
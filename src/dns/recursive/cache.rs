@@ -0,0 +1,574 @@
+// Answer cache for the recursive resolver
+//
+// Records are cached as RRsets keyed by (name, type, class). Each RRset is stored along with the
+// absolute time it expires, computed from the lowest TTL in the set (RFC 2181 says every TTL in
+// an RRset should match, but that's not something we can count on authorities getting right).
+// Records handed back out of the cache have their TTLs counted down to the time remaining, so
+// clients downstream of us don't hold onto them longer than the authority intended.
+//
+// Negative answers are cached too, following RFC 2308: an NXDOMAIN covers every type at a name,
+// while a NODATA only covers the type that was asked for. Either is kept for the lesser of the
+// SOA's own TTL and its MINIMUM field, and the SOA is handed back along with the cached answer.
+//
+// The cache holds a fixed number of entries; once it's full, the least recently used entry is
+// evicted to make room.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::dns::protocol::{lowercase_name, DnsClass, DnsRRType, DnsRecordData, DnsResourceRecord};
+
+// How many RRsets the global cache holds before it starts evicting
+pub const DEFAULT_CAPACITY: usize = 10000;
+
+// Upper bound on how long we'll keep anything, regardless of what the authority says. A week is
+// the default most other resolvers use (e.g. BIND's max-cache-ttl).
+const MAX_TTL: u32 = 7 * 24 * 60 * 60;
+
+// Negative answers get a much shorter cap; RFC 2308 suggests one to three hours
+const MAX_NEGATIVE_TTL: u32 = 3 * 60 * 60;
+
+// What a lookup can find in the cache
+#[derive(Clone, PartialEq, Debug)]
+pub enum CachedAnswer {
+    // The RRset asked for
+    Records(Vec<DnsResourceRecord>),
+    // The name exists, but has no records of the type asked for. Carries the zone's SOA.
+    NoData(DnsResourceRecord),
+    // The name doesn't exist at all. Carries the zone's SOA.
+    NXDomain(DnsResourceRecord),
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct CacheKey {
+    // DNS names compare case-insensitively (RFC 4343), so we always store them lowercased
+    name: Vec<String>,
+    // None for NXDOMAIN entries, which cover the whole name
+    rr_type: Option<DnsRRType>,
+    class: DnsClass,
+}
+
+impl CacheKey {
+    fn new(name: &[String], rr_type: Option<DnsRRType>, class: DnsClass) -> CacheKey {
+        CacheKey {
+            name: lowercase_name(name),
+            rr_type,
+            class,
+        }
+    }
+}
+
+struct CacheEntry {
+    // Either an RRset, or an SOA for a NoData/NXDomain answer. TTLs are the original ones.
+    answer: CachedAnswer,
+    expires: Instant,
+    // Value of the LRU clock the last time this entry was written or read
+    last_used: u64,
+}
+
+struct CacheInner {
+    entries: HashMap<CacheKey, CacheEntry>,
+    // Maps each entry's `last_used` tick back to its key, so the first item is always the least
+    // recently used entry
+    lru: BTreeMap<u64, CacheKey>,
+    // Every entry's key, grouped by name and class, so an NXDOMAIN can clear out what we had for
+    // the name without looking through the whole cache
+    names: HashMap<(Vec<String>, DnsClass), HashSet<CacheKey>>,
+    clock: u64,
+}
+
+impl CacheInner {
+    fn insert(&mut self, key: CacheKey, entry: CacheEntry) {
+        self.names
+            .entry((key.name.to_owned(), key.class))
+            .or_default()
+            .insert(key.to_owned());
+        self.entries.insert(key.to_owned(), entry);
+        self.touch(&key);
+    }
+
+    fn touch(&mut self, key: &CacheKey) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.last_used);
+            entry.last_used = clock;
+            self.lru.insert(clock, key.to_owned());
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+        }
+        let name = (key.name.to_owned(), key.class);
+        if let Some(keys) = self.names.get_mut(&name) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.names.remove(&name);
+            }
+        }
+    }
+
+    // Drop everything we have for `name`, whatever its type
+    fn remove_name(&mut self, name: &[String], class: DnsClass) {
+        let keys = self
+            .names
+            .remove(&(name.to_owned(), class))
+            .unwrap_or_default();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+}
+
+pub struct RecordCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+impl RecordCache {
+    pub fn new(capacity: usize) -> RecordCache {
+        RecordCache {
+            capacity,
+            inner: Mutex::new(CacheInner {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                names: HashMap::new(),
+                clock: 0,
+            }),
+        }
+    }
+
+    // Cache every RRset in `records`. Records are grouped by (name, type, class); a group
+    // replaces whatever we had cached for that RRset before.
+    pub fn insert(&self, records: &[DnsResourceRecord]) {
+        self.insert_at(records, Instant::now());
+    }
+
+    pub fn insert_at(&self, records: &[DnsResourceRecord], now: Instant) {
+        let mut rrsets: Vec<(CacheKey, Vec<DnsResourceRecord>)> = Vec::new();
+        for rr in records {
+            // OPT pseudo-records aren't data and shouldn't ever be cached
+            if rr.rr_type == DnsRRType::OPT {
+                continue;
+            }
+            let key = CacheKey::new(&rr.name, Some(rr.rr_type), rr.class);
+            match rrsets.iter_mut().find(|(k, _)| k == &key) {
+                Some((_, rrset)) => rrset.push(rr.to_owned()),
+                None => rrsets.push((key, vec![rr.to_owned()])),
+            }
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        for (key, rrset) in rrsets {
+            let ttl = rrset.iter().map(|rr| rr.ttl).min().unwrap_or(0);
+            self.store(
+                &mut inner,
+                key,
+                CachedAnswer::Records(rrset),
+                ttl.min(MAX_TTL),
+                now,
+            );
+        }
+    }
+
+    // Cache an NXDOMAIN for `name`, given the SOA from the authority section of the response
+    pub fn insert_nxdomain(&self, name: &[String], class: DnsClass, soa: &DnsResourceRecord) {
+        self.insert_nxdomain_at(name, class, soa, Instant::now());
+    }
+
+    pub fn insert_nxdomain_at(
+        &self,
+        name: &[String],
+        class: DnsClass,
+        soa: &DnsResourceRecord,
+        now: Instant,
+    ) {
+        let key = CacheKey::new(name, None, class);
+        let mut inner = self.inner.lock().unwrap();
+        // Anything we had for this name is now known not to exist
+        inner.remove_name(&key.name, class);
+        self.store(
+            &mut inner,
+            key,
+            CachedAnswer::NXDomain(soa.to_owned()),
+            negative_ttl(soa),
+            now,
+        );
+    }
+
+    // Cache a NODATA for (`name`, `rr_type`), given the SOA from the authority section
+    pub fn insert_nodata(
+        &self,
+        name: &[String],
+        rr_type: DnsRRType,
+        class: DnsClass,
+        soa: &DnsResourceRecord,
+    ) {
+        self.insert_nodata_at(name, rr_type, class, soa, Instant::now());
+    }
+
+    pub fn insert_nodata_at(
+        &self,
+        name: &[String],
+        rr_type: DnsRRType,
+        class: DnsClass,
+        soa: &DnsResourceRecord,
+        now: Instant,
+    ) {
+        let key = CacheKey::new(name, Some(rr_type), class);
+        let mut inner = self.inner.lock().unwrap();
+        self.store(
+            &mut inner,
+            key,
+            CachedAnswer::NoData(soa.to_owned()),
+            negative_ttl(soa),
+            now,
+        );
+    }
+
+    fn store(
+        &self,
+        inner: &mut CacheInner,
+        key: CacheKey,
+        answer: CachedAnswer,
+        ttl: u32,
+        now: Instant,
+    ) {
+        inner.remove(&key);
+        // A TTL of zero means the data is only good for the transaction it came in
+        if ttl == 0 {
+            return;
+        }
+        let expires = now + Duration::from_secs(ttl as u64);
+        inner.insert(
+            key,
+            CacheEntry {
+                answer,
+                expires,
+                last_used: 0,
+            },
+        );
+
+        while inner.entries.len() > self.capacity {
+            let oldest = match inner.lru.iter().next() {
+                Some((_, key)) => key.to_owned(),
+                None => break,
+            };
+            inner.remove(&oldest);
+        }
+    }
+
+    // Look up what we know about (`name`, `rr_type`), returning None if we don't have anything
+    // or it's expired. Records returned (including the SOA on negative answers) have their TTLs
+    // set to however much time is left before they expire.
+    pub fn lookup(
+        &self,
+        name: &[String],
+        rr_type: DnsRRType,
+        class: DnsClass,
+    ) -> Option<CachedAnswer> {
+        self.lookup_at(name, rr_type, class, Instant::now())
+    }
+
+    pub fn lookup_at(
+        &self,
+        name: &[String],
+        rr_type: DnsRRType,
+        class: DnsClass,
+        now: Instant,
+    ) -> Option<CachedAnswer> {
+        let mut inner = self.inner.lock().unwrap();
+        // A cached NXDOMAIN answers every type at the name
+        let nxdomain_key = CacheKey::new(name, None, class);
+        let typed_key = CacheKey::new(name, Some(rr_type), class);
+        for key in &[nxdomain_key, typed_key] {
+            let expires = match inner.entries.get(key) {
+                Some(entry) => entry.expires,
+                None => continue,
+            };
+            if now >= expires {
+                inner.remove(key);
+                continue;
+            }
+            inner.touch(key);
+
+            let remaining = (expires - now).as_secs() as u32;
+            let count_down = |rr: &DnsResourceRecord| DnsResourceRecord {
+                ttl: remaining,
+                ..rr.to_owned()
+            };
+            let answer = match &inner.entries[key].answer {
+                CachedAnswer::Records(records) => {
+                    CachedAnswer::Records(records.iter().map(count_down).collect())
+                }
+                CachedAnswer::NoData(soa) => CachedAnswer::NoData(count_down(soa)),
+                CachedAnswer::NXDomain(soa) => CachedAnswer::NXDomain(count_down(soa)),
+            };
+            return Some(answer);
+        }
+        None
+    }
+}
+
+// RFC 2308 section 5: negative answers are cached for the lesser of the SOA's TTL and its MINIMUM
+fn negative_ttl(soa: &DnsResourceRecord) -> u32 {
+    let minimum = match soa.record {
+        DnsRecordData::SOA { minimum, .. } => minimum,
+        _ => 0,
+    };
+    soa.ttl.min(minimum).min(MAX_NEGATIVE_TTL)
+}
+
+// The cache shared by every resolver thread
+pub fn global() -> &'static RecordCache {
+    static CACHE: OnceLock<RecordCache> = OnceLock::new();
+    CACHE.get_or_init(|| RecordCache::new(DEFAULT_CAPACITY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::protocol::*;
+
+    use std::net::Ipv4Addr;
+
+    fn a_record(name: &str, ttl: u32, last_octet: u8) -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: parse_name(name),
+            rr_type: DnsRRType::A,
+            class: DnsClass::IN,
+            ttl,
+            record: DnsRecordData::A(Ipv4Addr::new(192, 0, 2, last_octet)),
+        }
+    }
+
+    fn soa_record(zone: &str, ttl: u32, minimum: u32) -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: parse_name(zone),
+            rr_type: DnsRRType::SOA,
+            class: DnsClass::IN,
+            ttl,
+            record: DnsRecordData::SOA {
+                mname: parse_name("ns1.example.com"),
+                rname: parse_name("hostmaster.example.com"),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum,
+            },
+        }
+    }
+
+    #[test]
+    fn ttls_count_down_and_expire() {
+        let cache = RecordCache::new(10);
+        let start = Instant::now();
+        cache.insert_at(
+            &[
+                a_record("example.com", 300, 1),
+                a_record("example.com", 60, 2),
+            ],
+            start,
+        );
+
+        let records = cache
+            .lookup_at(
+                &parse_name("example.com"),
+                DnsRRType::A,
+                DnsClass::IN,
+                start,
+            )
+            .expect("RRset should be cached");
+        let records = match records {
+            CachedAnswer::Records(records) => records,
+            other => panic!("Expected records, got {:?}", other),
+        };
+        assert_eq!(records.len(), 2);
+        // The whole RRset expires with its lowest TTL
+        assert!(records.iter().all(|rr| rr.ttl == 60));
+
+        let later = start + Duration::from_secs(45);
+        let records = cache
+            .lookup_at(
+                &parse_name("example.com"),
+                DnsRRType::A,
+                DnsClass::IN,
+                later,
+            )
+            .expect("RRset should still be cached");
+        let records = match records {
+            CachedAnswer::Records(records) => records,
+            other => panic!("Expected records, got {:?}", other),
+        };
+        assert!(records.iter().all(|rr| rr.ttl == 15));
+
+        let expired = start + Duration::from_secs(60);
+        assert_eq!(
+            cache.lookup_at(
+                &parse_name("example.com"),
+                DnsRRType::A,
+                DnsClass::IN,
+                expired
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn lookups_ignore_case_and_type() {
+        let cache = RecordCache::new(10);
+        let now = Instant::now();
+        cache.insert_at(&[a_record("Example.COM", 300, 1)], now);
+
+        assert!(cache
+            .lookup_at(&parse_name("example.com"), DnsRRType::A, DnsClass::IN, now)
+            .is_some());
+        assert!(cache
+            .lookup_at(
+                &parse_name("example.com"),
+                DnsRRType::AAAA,
+                DnsClass::IN,
+                now
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn zero_ttl_is_not_cached() {
+        let cache = RecordCache::new(10);
+        let now = Instant::now();
+        cache.insert_at(&[a_record("example.com", 0, 1)], now);
+        assert!(cache
+            .lookup_at(&parse_name("example.com"), DnsRRType::A, DnsClass::IN, now)
+            .is_none());
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let cache = RecordCache::new(2);
+        let now = Instant::now();
+        cache.insert_at(&[a_record("one.example", 300, 1)], now);
+        cache.insert_at(&[a_record("two.example", 300, 2)], now);
+        // Reading "one" makes "two" the least recently used entry
+        cache.lookup_at(&parse_name("one.example"), DnsRRType::A, DnsClass::IN, now);
+        cache.insert_at(&[a_record("three.example", 300, 3)], now);
+
+        assert!(cache
+            .lookup_at(&parse_name("one.example"), DnsRRType::A, DnsClass::IN, now)
+            .is_some());
+        assert!(cache
+            .lookup_at(&parse_name("two.example"), DnsRRType::A, DnsClass::IN, now)
+            .is_none());
+        assert!(cache
+            .lookup_at(
+                &parse_name("three.example"),
+                DnsRRType::A,
+                DnsClass::IN,
+                now
+            )
+            .is_some());
+    }
+
+    #[test]
+    fn nxdomain_covers_every_type() {
+        let cache = RecordCache::new(10);
+        let now = Instant::now();
+        let soa = soa_record("example.com", 3600, 300);
+        cache.insert_at(&[a_record("typo.example.com", 300, 1)], now);
+        cache.insert_nxdomain_at(&parse_name("typo.example.com"), DnsClass::IN, &soa, now);
+
+        for rr_type in &[DnsRRType::A, DnsRRType::AAAA, DnsRRType::MX] {
+            match cache.lookup_at(&parse_name("typo.example.com"), *rr_type, DnsClass::IN, now) {
+                Some(CachedAnswer::NXDomain(cached_soa)) => {
+                    assert_eq!(cached_soa.record, soa.record);
+                    // TTL is min(SOA TTL, SOA MINIMUM)
+                    assert_eq!(cached_soa.ttl, 300);
+                }
+                other => panic!("Expected NXDOMAIN, got {:?}", other),
+            }
+        }
+
+        let expired = now + Duration::from_secs(300);
+        assert_eq!(
+            cache.lookup_at(
+                &parse_name("typo.example.com"),
+                DnsRRType::A,
+                DnsClass::IN,
+                expired
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn nxdomain_only_clears_its_own_name() {
+        let cache = RecordCache::new(2);
+        let now = Instant::now();
+        let soa = soa_record("example.com", 3600, 300);
+        cache.insert_at(&[a_record("typo.example.com", 300, 1)], now);
+        cache.insert_at(&[a_record("www.example.com", 300, 2)], now);
+        cache.insert_nxdomain_at(&parse_name("Typo.example.com"), DnsClass::IN, &soa, now);
+
+        assert!(cache
+            .lookup_at(
+                &parse_name("www.example.com"),
+                DnsRRType::A,
+                DnsClass::IN,
+                now
+            )
+            .is_some());
+        // The A record made way for the NXDOMAIN, rather than anything being evicted
+        let inner = cache.inner.lock().unwrap();
+        assert_eq!(inner.entries.len(), 2);
+        assert_eq!(inner.names.len(), 2);
+        drop(inner);
+
+        // Evicted entries leave the index too
+        cache.insert_at(&[a_record("new.example.com", 300, 3)], now);
+        let inner = cache.inner.lock().unwrap();
+        assert_eq!(inner.entries.len(), 2);
+        assert_eq!(inner.names.len(), 2);
+        assert!(!inner
+            .names
+            .contains_key(&(parse_name("typo.example.com"), DnsClass::IN)));
+    }
+
+    #[test]
+    fn nodata_covers_only_its_type() {
+        let cache = RecordCache::new(10);
+        let now = Instant::now();
+        let soa = soa_record("example.com", 60, 300);
+        cache.insert_at(&[a_record("www.example.com", 300, 1)], now);
+        cache.insert_nodata_at(
+            &parse_name("www.example.com"),
+            DnsRRType::AAAA,
+            DnsClass::IN,
+            &soa,
+            now,
+        );
+
+        let later = now + Duration::from_secs(20);
+        match cache.lookup_at(
+            &parse_name("www.example.com"),
+            DnsRRType::AAAA,
+            DnsClass::IN,
+            later,
+        ) {
+            Some(CachedAnswer::NoData(cached_soa)) => assert_eq!(cached_soa.ttl, 40),
+            other => panic!("Expected NODATA, got {:?}", other),
+        }
+        match cache.lookup_at(
+            &parse_name("www.example.com"),
+            DnsRRType::A,
+            DnsClass::IN,
+            later,
+        ) {
+            Some(CachedAnswer::Records(records)) => assert_eq!(records.len(), 1),
+            other => panic!("Expected records, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,348 @@
+// CNAME chains
+//
+// An answer to "www.example.com A" might be a CNAME to "www.example.net", which is a CNAME to
+// "example.net.cdn.net", which finally has the A records. Authorities include as much of the chain
+// as they're authoritative for, so some (or all) of it can come in a single response, and we only
+// have to go looking for the rest. Chains can also be broken: they can loop back on themselves, or
+// go on for longer than anyone would reasonably need, and we give up on those rather than chasing
+// them forever.
+//
+// DNAME records (RFC 6672) fit into the same scheme. A DNAME at "example.com" pointing to
+// "example.net" acts like a CNAME from every name under example.com to the same name under
+// example.net, so when we find one above a name in the chain, we make the CNAME for that name
+// ourselves. Authorities send along the CNAME they synthesized too, but we don't take their word
+// for it: if it doesn't match what the DNAME says, it's ignored.
+
+use super::errors::ResolutionError;
+use crate::dns::protocol::{
+    dname_substitute, lowercase_name, DnsRRType, DnsRecordData, DnsResourceRecord,
+};
+
+// Real chains are rarely more than two or three links long
+pub const MAX_CHAIN_LENGTH: usize = 8;
+
+#[derive(Debug, PartialEq)]
+pub struct Chain {
+    // The CNAME records leading from the name we asked about to `target`, in order, along with the
+    // DNAME records any of them were synthesized from
+    pub links: Vec<DnsResourceRecord>,
+    // The name at the end of the chain (the name we asked about, if there were no CNAMEs)
+    pub target: Vec<String>,
+    // The records of the type we asked for at `target`, if we have them
+    pub answers: Vec<DnsResourceRecord>,
+}
+
+impl Chain {
+    // Has the chain reached the records we're after? If not, someone needs to go ask about
+    // `target`.
+    pub fn is_complete(&self) -> bool {
+        !self.answers.is_empty()
+    }
+
+    // Continue this chain with the one we found by asking about its target
+    pub fn extend(&mut self, rest: Chain) -> Result<(), ResolutionError> {
+        for link in rest.links {
+            if self.visits(&link.name) {
+                return Err(loop_error(&link.name));
+            }
+            self.push(link)?;
+        }
+        self.target = rest.target;
+        self.answers = rest.answers;
+        Ok(())
+    }
+
+    // Every record in the chain, in the order they belong in an answer section
+    pub fn into_records(self) -> Vec<DnsResourceRecord> {
+        let mut records = self.links;
+        records.extend(self.answers);
+        records
+    }
+
+    fn cnames(&self) -> impl Iterator<Item = &DnsResourceRecord> {
+        self.links
+            .iter()
+            .filter(|link| link.rr_type == DnsRRType::CNAME)
+    }
+
+    fn visits(&self, name: &[String]) -> bool {
+        let name = lowercase_name(name);
+        self.cnames().any(|link| lowercase_name(&link.name) == name)
+    }
+
+    fn push(&mut self, link: DnsResourceRecord) -> Result<(), ResolutionError> {
+        if link.rr_type == DnsRRType::DNAME {
+            // Several names in a chain can sit under the same DNAME, but it only needs to be in
+            // the answer once
+            if !self.links.contains(&link) {
+                self.links.push(link);
+            }
+            return Ok(());
+        }
+        if self.cnames().count() >= MAX_CHAIN_LENGTH {
+            return Err(ResolutionError::ChainTooLong(MAX_CHAIN_LENGTH));
+        }
+        self.links.push(link);
+        Ok(())
+    }
+}
+
+// Follow the chain from `qname` through the records in an answer section, as far as those records
+// take us. A CNAME is only an alias to follow if it isn't what we asked for; a question for the
+// CNAME itself is answered by the first link.
+pub fn follow(
+    qname: &[String],
+    qtype: DnsRRType,
+    records: &[DnsResourceRecord],
+) -> Result<Chain, ResolutionError> {
+    let mut chain = Chain {
+        links: vec![],
+        target: qname.to_owned(),
+        answers: vec![],
+    };
+    loop {
+        let target = lowercase_name(&chain.target);
+        let owned_by_target = || {
+            records
+                .iter()
+                .filter(|rr| lowercase_name(&rr.name) == target)
+        };
+
+        chain.answers = owned_by_target()
+            .filter(|rr| qtype == DnsRRType::ANY || rr.rr_type == qtype)
+            .cloned()
+            .collect();
+        if chain.is_complete() {
+            return Ok(chain);
+        }
+
+        // A name with a CNAME can't have any other data, so there should be only one; if a
+        // server sends us several, we go with the first
+        let cname = owned_by_target().find_map(|rr| match &rr.record {
+            DnsRecordData::CNAME(next) => Some((rr, next)),
+            _ => None,
+        });
+        let (links, next) = match (synthesize_cname(&chain.target, records), cname) {
+            (Some((dname, synthesized, next)), sent) => {
+                if let Some((_, sent_next)) = sent {
+                    if lowercase_name(sent_next) != lowercase_name(&next) {
+                        log!(
+                            "Ignoring CNAME to {:?} that doesn't match DNAME {:?}",
+                            sent_next,
+                            dname
+                        );
+                    }
+                }
+                (vec![dname.to_owned(), synthesized], next)
+            }
+            (None, Some((link, next))) => (vec![link.to_owned()], next.to_owned()),
+            (None, None) => return Ok(chain),
+        };
+        if chain.visits(&next) || lowercase_name(&next) == lowercase_name(qname) {
+            return Err(loop_error(&next));
+        }
+        for link in links {
+            chain.push(link)?;
+        }
+        chain.target = next;
+    }
+}
+
+// If there's a DNAME among `records` that redirects `name`, returns it along with the CNAME it
+// implies for `name` and that CNAME's target. The synthesized CNAME gets the DNAME's TTL (RFC 6672
+// section 3.1). When more than one DNAME applies, the one closest to `name` wins, the same way it
+// would have been found walking down the tree.
+fn synthesize_cname<'a>(
+    name: &[String],
+    records: &'a [DnsResourceRecord],
+) -> Option<(&'a DnsResourceRecord, DnsResourceRecord, Vec<String>)> {
+    records
+        .iter()
+        .filter_map(|rr| match &rr.record {
+            DnsRecordData::DNAME(target) => {
+                dname_substitute(name, &rr.name, target).map(|next| (rr, next))
+            }
+            _ => None,
+        })
+        .max_by_key(|(dname, _)| dname.name.len())
+        .map(|(dname, next)| {
+            let cname = DnsResourceRecord {
+                name: name.to_owned(),
+                rr_type: DnsRRType::CNAME,
+                class: dname.class,
+                ttl: dname.ttl,
+                record: DnsRecordData::CNAME(next.to_owned()),
+            };
+            (dname, cname, next)
+        })
+}
+
+fn loop_error(name: &[String]) -> ResolutionError {
+    ResolutionError::Loop(format!("CNAME chain at {}", name.join(".")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::protocol::*;
+
+    use std::net::Ipv4Addr;
+
+    fn cname(name: &str, target: &str) -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: parse_name(name),
+            rr_type: DnsRRType::CNAME,
+            class: DnsClass::IN,
+            ttl: 300,
+            record: DnsRecordData::CNAME(parse_name(target)),
+        }
+    }
+
+    fn a_record(name: &str) -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: parse_name(name),
+            rr_type: DnsRRType::A,
+            class: DnsClass::IN,
+            ttl: 300,
+            record: DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+        }
+    }
+
+    #[test]
+    fn chain_in_one_answer_is_followed() {
+        // Deliberately out of order, with an unrelated record mixed in
+        let records = vec![
+            a_record("cdn.example.net"),
+            cname("www.example.net", "CDN.example.net"),
+            a_record("mail.example.com"),
+            cname("www.example.com", "www.example.net"),
+        ];
+        let chain = follow(&parse_name("www.example.com"), DnsRRType::A, &records).unwrap();
+        assert!(chain.is_complete());
+        assert_eq!(
+            chain.into_records(),
+            vec![
+                cname("www.example.com", "www.example.net"),
+                cname("www.example.net", "CDN.example.net"),
+                a_record("cdn.example.net"),
+            ]
+        );
+    }
+
+    #[test]
+    fn incomplete_chain_stops_at_the_last_link() {
+        let records = vec![cname("www.example.com", "www.example.net")];
+        let chain = follow(&parse_name("www.example.com"), DnsRRType::A, &records).unwrap();
+        assert!(!chain.is_complete());
+        assert_eq!(chain.target, parse_name("www.example.net"));
+        assert_eq!(chain.links.len(), 1);
+    }
+
+    #[test]
+    fn cname_question_is_answered_by_the_cname() {
+        let records = vec![
+            cname("www.example.com", "www.example.net"),
+            cname("www.example.net", "cdn.example.net"),
+        ];
+        let chain = follow(&parse_name("www.example.com"), DnsRRType::CNAME, &records).unwrap();
+        assert!(chain.links.is_empty());
+        assert_eq!(chain.answers, vec![records[0].to_owned()]);
+    }
+
+    #[test]
+    fn loops_are_detected() {
+        let records = vec![
+            cname("a.example.com", "b.example.com"),
+            cname("b.example.com", "A.example.com"),
+        ];
+        assert!(matches!(
+            follow(&parse_name("a.example.com"), DnsRRType::A, &records),
+            Err(ResolutionError::Loop(_))
+        ));
+
+        // Including when the loop only shows up once we put two answers together
+        let mut chain = follow(&parse_name("a.example.com"), DnsRRType::A, &records[..1]).unwrap();
+        let rest = Chain {
+            links: vec![
+                cname("b.example.com", "c.example.com"),
+                cname("c.example.com", "a.example.com"),
+                cname("a.example.com", "b.example.com"),
+            ],
+            target: parse_name("b.example.com"),
+            answers: vec![],
+        };
+        assert!(matches!(chain.extend(rest), Err(ResolutionError::Loop(_))));
+    }
+
+    #[test]
+    fn long_chains_are_cut_off() {
+        let records: Vec<DnsResourceRecord> = (0..=MAX_CHAIN_LENGTH)
+            .map(|i| {
+                cname(
+                    &format!("{}.example.com", i),
+                    &format!("{}.example.com", i + 1),
+                )
+            })
+            .collect();
+        assert_eq!(
+            follow(&parse_name("0.example.com"), DnsRRType::A, &records),
+            Err(ResolutionError::ChainTooLong(MAX_CHAIN_LENGTH))
+        );
+    }
+
+    fn dname(name: &str, target: &str) -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: parse_name(name),
+            rr_type: DnsRRType::DNAME,
+            class: DnsClass::IN,
+            ttl: 600,
+            record: DnsRecordData::DNAME(parse_name(target)),
+        }
+    }
+
+    #[test]
+    fn dname_synthesizes_cname() {
+        let records = vec![
+            dname("example.com", "example.net"),
+            a_record("www.example.net"),
+        ];
+        let chain = follow(&parse_name("www.example.com"), DnsRRType::A, &records).unwrap();
+        let synthesized = DnsResourceRecord {
+            ttl: 600,
+            ..cname("www.example.com", "www.example.net")
+        };
+        assert_eq!(
+            chain.into_records(),
+            vec![
+                dname("example.com", "example.net"),
+                synthesized,
+                a_record("www.example.net"),
+            ]
+        );
+    }
+
+    #[test]
+    fn forged_synthesized_cname_is_ignored() {
+        // The CNAME claims to come from the DNAME, but points somewhere else entirely
+        let records = vec![
+            dname("example.com", "example.net"),
+            cname("www.example.com", "www.attacker.org"),
+            a_record("www.attacker.org"),
+        ];
+        let chain = follow(&parse_name("www.example.com"), DnsRRType::A, &records).unwrap();
+        assert!(!chain.is_complete());
+        assert_eq!(chain.target, parse_name("www.example.net"));
+    }
+
+    #[test]
+    fn dname_at_the_question_is_an_answer() {
+        let records = vec![dname("example.com", "example.net")];
+        let chain = follow(&parse_name("example.com"), DnsRRType::DNAME, &records).unwrap();
+        assert_eq!(chain.answers, records);
+
+        // And a DNAME doesn't apply to its own owner name
+        let chain = follow(&parse_name("example.com"), DnsRRType::A, &records).unwrap();
+        assert!(chain.links.is_empty());
+        assert!(!chain.is_complete());
+    }
+}
@@ -0,0 +1,133 @@
+// Resolver settings
+//
+// These are set once when the server starts up (see `main`) and then read by every resolver
+// thread. Anything that resolves before `configure` is called gets the defaults.
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use super::forward::ForwardStrategy;
+use super::minimise::QnameMinimisation;
+use super::query::{self, QueryOptions};
+use super::root::{self, RootHints, RootSelection};
+use super::zones::ZoneServers;
+use crate::dns::protocol::DnsRRType;
+
+#[derive(Clone, Debug, Default)]
+pub struct ResolverConfig {
+    // Timeouts and retransmission for queries to authorities
+    pub query: QueryOptions,
+    // A named.root file to load root hints from, instead of using the builtin ones
+    pub root_hints: Option<PathBuf>,
+    // Which root server to start lookups at
+    pub root_selection: RootSelection,
+    // Which address families to talk to authorities over
+    pub ip_preference: IpPreference,
+    // How much of each question to reveal to the servers above the zone that holds it
+    pub qname_minimisation: QnameMinimisation,
+    // How often to log metrics and per-server statistics, if at all
+    pub stats_interval: Option<Duration>,
+    // Upstream resolvers to forward recursive queries to, instead of resolving them ourselves
+    pub forwarders: Vec<SocketAddr>,
+    // Which upstream to ask first
+    pub forward_strategy: ForwardStrategy,
+    // Domains to forward to their own resolvers, or to start iterating at their own authorities,
+    // rather than going through the usual resolution
+    pub forward_zones: Vec<ZoneServers>,
+    pub stub_zones: Vec<ZoneServers>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum IpPreference {
+    V4,
+    V6,
+    // Use IPv6 alongside IPv4, as long as this machine can open an IPv6 socket at all
+    #[default]
+    Both,
+}
+
+impl IpPreference {
+    // Should we send queries to this address?
+    pub fn allows(self, address: &IpAddr) -> bool {
+        match (self, address) {
+            (IpPreference::V4, IpAddr::V4(_)) | (IpPreference::Both, IpAddr::V4(_)) => true,
+            (IpPreference::V6, IpAddr::V6(_)) => true,
+            (IpPreference::Both, IpAddr::V6(_)) => query::ipv6_available(),
+            _ => false,
+        }
+    }
+
+    // The record types to look up when we need a nameserver's address
+    pub fn address_types(self) -> Vec<DnsRRType> {
+        match self {
+            IpPreference::V4 => vec![DnsRRType::A],
+            IpPreference::V6 => vec![DnsRRType::AAAA],
+            IpPreference::Both if query::ipv6_available() => vec![DnsRRType::A, DnsRRType::AAAA],
+            IpPreference::Both => vec![DnsRRType::A],
+        }
+    }
+}
+
+impl FromStr for IpPreference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IpPreference, String> {
+        match s {
+            "v4" => Ok(IpPreference::V4),
+            "v6" => Ok(IpPreference::V6),
+            "both" => Ok(IpPreference::Both),
+            _ => Err(format!("Unknown IP preference {}", s)),
+        }
+    }
+}
+
+static CONFIG: OnceLock<ResolverConfig> = OnceLock::new();
+
+// Install the resolver configuration. This can only happen once; returns an error if the resolver
+// has already been configured (or has already started using the defaults).
+pub fn configure(config: ResolverConfig) -> Result<(), String> {
+    if let Some(path) = &config.root_hints {
+        root::install(RootHints::load(path)?)?;
+    }
+    // Stub zone servers are authorities like any other, so they get asked on port 53
+    for stub in &config.stub_zones {
+        if let Some(server) = stub.servers.iter().find(|server| server.port() != 53) {
+            return Err(format!(
+                "Stub zone servers have to use port 53, got {} for {:?}",
+                server, stub.zone
+            ));
+        }
+    }
+    CONFIG
+        .set(config)
+        .map_err(|_| "Resolver configuration was already set".to_owned())
+}
+
+pub fn get() -> &'static ResolverConfig {
+    CONFIG.get_or_init(ResolverConfig::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn ip_preference_filters_addresses() {
+        let v4 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53));
+        let v6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x53));
+        assert!(IpPreference::V4.allows(&v4));
+        assert!(!IpPreference::V4.allows(&v6));
+        assert!(!IpPreference::V6.allows(&v4));
+        assert!(IpPreference::V6.allows(&v6));
+        assert!(IpPreference::Both.allows(&v4));
+
+        assert_eq!(IpPreference::V6.address_types(), vec![DnsRRType::AAAA]);
+        assert_eq!("both".parse(), Ok(IpPreference::Both));
+        assert!("v5".parse::<IpPreference>().is_err());
+    }
+}
@@ -0,0 +1,199 @@
+// Per-question resolution state
+//
+// Resolving one client question can involve resolving several others: each CNAME in a chain is a
+// new question, and so is every nameserver we get referred to without glue. A misconfigured (or
+// malicious) set of zones can turn that into an infinite loop, e.g. "example.com" delegated to
+// "ns.example.net" while "example.net" is delegated to "ns.example.com". A ResolutionContext is
+// created for each client question and passed through every lookup made on its behalf, so we can
+// notice when that's happening and give up.
+//
+// Lookups that run side by side (like the async resolver's A and AAAA lookups for a nameserver)
+// each get a fork of the context: the same questions in flight so far, and a share of the same
+// query budget.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use super::errors::ResolutionError;
+use crate::dns::protocol::{lowercase_name, DnsQuestion, DnsRRType};
+
+// How deeply lookups can nest (BIND's max-recursion-depth defaults to 7; we allow a few more
+// since we also count CNAME chases)
+pub const MAX_DEPTH: usize = 12;
+
+// How many queries we'll send to authorities for one client question (same as BIND's
+// max-recursion-queries)
+pub const MAX_QUERIES: usize = 100;
+
+pub struct ResolutionContext {
+    // Questions currently being resolved somewhere up the call stack
+    questions: Vec<(Vec<String>, DnsRRType)>,
+    // Nameservers we're currently looking up addresses for
+    nameservers: Vec<Vec<String>>,
+    // Shared with any forks
+    queries_sent: Arc<AtomicUsize>,
+}
+
+impl ResolutionContext {
+    pub fn new() -> ResolutionContext {
+        ResolutionContext {
+            questions: Vec::new(),
+            nameservers: Vec::new(),
+            queries_sent: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    // A context for a lookup that runs alongside others made on behalf of this one
+    #[cfg(any(test, feature = "async"))]
+    pub fn fork(&self) -> ResolutionContext {
+        ResolutionContext {
+            questions: self.questions.to_owned(),
+            nameservers: self.nameservers.to_owned(),
+            queries_sent: Arc::clone(&self.queries_sent),
+        }
+    }
+
+    fn depth(&self) -> usize {
+        self.questions.len()
+    }
+
+    // Mark a question as in flight. Every successful `enter` must be paired with an `exit` once
+    // the question is resolved (or fails).
+    pub fn enter(&mut self, question: &DnsQuestion) -> Result<(), ResolutionError> {
+        let key = (lowercase_name(&question.qname), question.qtype);
+        if self.questions.contains(&key) {
+            return Err(ResolutionError::Loop(format!(
+                "{} {:?}",
+                question.qname.join("."),
+                question.qtype
+            )));
+        }
+        if self.depth() >= MAX_DEPTH {
+            return Err(ResolutionError::DepthExceeded(MAX_DEPTH));
+        }
+        self.questions.push(key);
+        Ok(())
+    }
+
+    pub fn exit(&mut self) {
+        self.questions.pop();
+    }
+
+    // Mark a nameserver's address as being looked up. As with questions, pair this with
+    // `exit_nameserver`.
+    pub fn enter_nameserver(&mut self, ns_name: &[String]) -> Result<(), ResolutionError> {
+        let key = lowercase_name(ns_name);
+        if self.nameservers.contains(&key) {
+            return Err(ResolutionError::Loop(format!(
+                "nameserver {}",
+                ns_name.join(".")
+            )));
+        }
+        self.nameservers.push(key);
+        Ok(())
+    }
+
+    pub fn exit_nameserver(&mut self) {
+        self.nameservers.pop();
+    }
+
+    // Account for a query about to be sent to an authority
+    pub fn count_query(&mut self) -> Result<(), ResolutionError> {
+        self.queries_sent
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sent| {
+                (sent < MAX_QUERIES).then_some(sent + 1)
+            })
+            .map(|_| ())
+            .map_err(|_| ResolutionError::QueryLimitExceeded(MAX_QUERIES))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::protocol::*;
+    use crate::dns::recursive::testing::question;
+
+    #[test]
+    fn repeated_question_is_a_loop() {
+        let mut context = ResolutionContext::new();
+        context
+            .enter(&question("www.example.com", DnsRRType::A))
+            .expect("First entry should be fine");
+        // Same name with a different type is a different question
+        context
+            .enter(&question("www.example.com", DnsRRType::AAAA))
+            .expect("Different type should be fine");
+        let result = context.enter(&question("WWW.example.com", DnsRRType::A));
+        assert!(matches!(result, Err(ResolutionError::Loop(_))));
+
+        // Once we've finished with it, asking again is fine
+        context.exit();
+        context.exit();
+        context
+            .enter(&question("www.example.com", DnsRRType::A))
+            .expect("Question should no longer be in flight");
+    }
+
+    #[test]
+    fn nameserver_lookup_loop_is_detected() {
+        let mut context = ResolutionContext::new();
+        let ns_name = vec!["ns".to_owned(), "example".to_owned(), "com".to_owned()];
+        context.enter_nameserver(&ns_name).expect("First lookup");
+        assert!(matches!(
+            context.enter_nameserver(&ns_name),
+            Err(ResolutionError::Loop(_))
+        ));
+        context.exit_nameserver();
+        context.enter_nameserver(&ns_name).expect("Lookup finished");
+    }
+
+    #[test]
+    fn depth_and_query_limits() {
+        let mut context = ResolutionContext::new();
+        for i in 0..MAX_DEPTH {
+            context
+                .enter(&question(&format!("{}.example.com", i), DnsRRType::A))
+                .expect("Should be within depth limit");
+        }
+        assert_eq!(
+            context.enter(&question("deep.example.com", DnsRRType::A)),
+            Err(ResolutionError::DepthExceeded(MAX_DEPTH))
+        );
+
+        for _ in 0..MAX_QUERIES {
+            context.count_query().expect("Should be within query limit");
+        }
+        assert_eq!(
+            context.count_query(),
+            Err(ResolutionError::QueryLimitExceeded(MAX_QUERIES))
+        );
+    }
+
+    #[test]
+    fn forks_share_the_query_budget() {
+        let mut context = ResolutionContext::new();
+        context
+            .enter(&question("www.example.com", DnsRRType::A))
+            .expect("First entry should be fine");
+        let mut fork = context.fork();
+        // The fork knows what's already in flight...
+        assert!(matches!(
+            fork.enter(&question("www.example.com", DnsRRType::A)),
+            Err(ResolutionError::Loop(_))
+        ));
+        // ...but what it enters doesn't affect the original
+        fork.enter(&question("ns.example.com", DnsRRType::A))
+            .expect("New question");
+        context
+            .enter(&question("ns.example.com", DnsRRType::A))
+            .expect("Only in flight in the fork");
+
+        for _ in 0..MAX_QUERIES / 2 {
+            fork.count_query().expect("Should be within query limit");
+            context.count_query().expect("Should be within query limit");
+        }
+        assert!(fork.count_query().is_err());
+        assert!(context.count_query().is_err());
+    }
+}
@@ -0,0 +1,271 @@
+// Delegation (infrastructure) cache for the recursive resolver
+//
+// The answer cache only helps when someone asks the exact same question again. This cache holds
+// on to the zone cuts we've learned about along the way: for each zone, the NS RRset the parent
+// handed us in a referral and whatever addresses we know for those nameservers. When a new
+// question comes in, we can start from the closest enclosing zone we know about instead of going
+// all the way back to the root.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::dns::protocol::lowercase_name;
+
+// How many zones the global cache holds on to
+pub const DEFAULT_CAPACITY: usize = 5000;
+
+// Cap on how long we'll trust a delegation, same as BIND's default max-cache-ttl
+const MAX_TTL: u32 = 7 * 24 * 60 * 60;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Nameserver {
+    pub name: Vec<String>,
+    // Addresses we know for this server, either from glue or from resolving its name. May be
+    // empty, in which case the resolver has to go look them up.
+    pub addresses: Vec<IpAddr>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Delegation {
+    pub zone: Vec<String>,
+    pub nameservers: Vec<Nameserver>,
+}
+
+impl Delegation {
+    // Every address we know of for this zone's servers, in the order the servers were given
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        self.nameservers
+            .iter()
+            .flat_map(|ns| ns.addresses.iter().cloned())
+            .collect()
+    }
+}
+
+struct DelegationEntry {
+    delegation: Delegation,
+    expires: Instant,
+}
+
+pub struct DelegationCache {
+    capacity: usize,
+    // Keyed by the lowercased zone name
+    zones: Mutex<HashMap<Vec<String>, DelegationEntry>>,
+}
+
+impl DelegationCache {
+    pub fn new(capacity: usize) -> DelegationCache {
+        DelegationCache {
+            capacity,
+            zones: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Store a zone cut, replacing whatever we knew about the zone before. `ttl` should be the
+    // lowest TTL of the NS RRset (and any glue) the delegation came from.
+    pub fn insert(&self, delegation: Delegation, ttl: u32) {
+        self.insert_at(delegation, ttl, Instant::now());
+    }
+
+    pub fn insert_at(&self, delegation: Delegation, ttl: u32, now: Instant) {
+        if ttl == 0 || delegation.nameservers.is_empty() {
+            return;
+        }
+        let key = lowercase_name(&delegation.zone);
+        let expires = now + Duration::from_secs(ttl.min(MAX_TTL) as u64);
+        let mut zones = self.zones.lock().unwrap();
+        zones.insert(
+            key,
+            DelegationEntry {
+                delegation,
+                expires,
+            },
+        );
+
+        if zones.len() > self.capacity {
+            // Throw out anything that's expired, and if that's not enough, whatever was going to
+            // expire soonest anyways
+            zones.retain(|_, entry| entry.expires > now);
+            while zones.len() > self.capacity {
+                let soonest = zones
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(key, _)| key.to_owned());
+                match soonest {
+                    Some(key) => zones.remove(&key),
+                    None => break,
+                };
+            }
+        }
+    }
+
+    // Record an address we looked up for one of a zone's nameservers (i.e. a server we didn't
+    // get glue for)
+    pub fn add_address(&self, zone: &[String], ns_name: &[String], address: IpAddr) {
+        let key = lowercase_name(zone);
+        let ns_name = lowercase_name(ns_name);
+        let mut zones = self.zones.lock().unwrap();
+        if let Some(entry) = zones.get_mut(&key) {
+            for ns in entry.delegation.nameservers.iter_mut() {
+                if lowercase_name(&ns.name) == ns_name && !ns.addresses.contains(&address) {
+                    ns.addresses.push(address);
+                }
+            }
+        }
+    }
+
+    // Find the deepest zone we have a usable delegation for that encloses `name`. Only zones
+    // where we know at least one nameserver address count; otherwise we'd need to resolve a
+    // nameserver's name before we could use the delegation, which may well lead us right back
+    // here.
+    pub fn closest(&self, name: &[String]) -> Option<Delegation> {
+        self.closest_at(name, Instant::now())
+    }
+
+    pub fn closest_at(&self, name: &[String], now: Instant) -> Option<Delegation> {
+        let name = lowercase_name(name);
+        let mut zones = self.zones.lock().unwrap();
+        // Walk up from the full name towards the root, one label at a time. We never cache the
+        // root itself; the root hints cover that.
+        for start in 0..name.len() {
+            let zone = &name[start..];
+            let expired = match zones.get(zone) {
+                Some(entry) => entry.expires <= now,
+                None => continue,
+            };
+            if expired {
+                zones.remove(zone);
+                continue;
+            }
+            let delegation = &zones[zone].delegation;
+            if !delegation.addresses().is_empty() {
+                return Some(delegation.to_owned());
+            }
+        }
+        None
+    }
+}
+
+// The delegation cache shared by every resolver thread
+pub fn global() -> &'static DelegationCache {
+    static CACHE: OnceLock<DelegationCache> = OnceLock::new();
+    CACHE.get_or_init(|| DelegationCache::new(DEFAULT_CAPACITY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::protocol::parse_name;
+
+    use std::net::Ipv4Addr;
+
+    fn delegation(zone: &str, ns: &str, address: Option<Ipv4Addr>) -> Delegation {
+        Delegation {
+            zone: parse_name(zone),
+            nameservers: vec![Nameserver {
+                name: parse_name(ns),
+                addresses: address.into_iter().map(IpAddr::V4).collect(),
+            }],
+        }
+    }
+
+    #[test]
+    fn closest_enclosing_zone_wins() {
+        let cache = DelegationCache::new(10);
+        let now = Instant::now();
+        cache.insert_at(
+            delegation(
+                "com",
+                "a.gtld-servers.net",
+                Some(Ipv4Addr::new(192, 5, 6, 30)),
+            ),
+            172800,
+            now,
+        );
+        cache.insert_at(
+            delegation(
+                "example.com",
+                "a.iana-servers.net",
+                Some(Ipv4Addr::new(199, 43, 135, 53)),
+            ),
+            172800,
+            now,
+        );
+
+        let found = cache
+            .closest_at(&parse_name("www.Example.com"), now)
+            .expect("Should find a delegation");
+        assert_eq!(found.zone, parse_name("example.com"));
+
+        let found = cache
+            .closest_at(&parse_name("example.org"), now)
+            .map(|d| d.zone);
+        assert_eq!(found, None);
+
+        let found = cache
+            .closest_at(&parse_name("www.example.net"), now)
+            .map(|d| d.zone);
+        assert_eq!(found, None);
+
+        let found = cache
+            .closest_at(&parse_name("other.com"), now)
+            .map(|d| d.zone);
+        assert_eq!(found, Some(parse_name("com")));
+    }
+
+    #[test]
+    fn delegations_without_addresses_are_skipped_until_resolved() {
+        let cache = DelegationCache::new(10);
+        let now = Instant::now();
+        cache.insert_at(
+            delegation(
+                "com",
+                "a.gtld-servers.net",
+                Some(Ipv4Addr::new(192, 5, 6, 30)),
+            ),
+            172800,
+            now,
+        );
+        cache.insert_at(
+            delegation("example.com", "ns.example.net", None),
+            172800,
+            now,
+        );
+
+        let found = cache.closest_at(&parse_name("www.example.com"), now);
+        assert_eq!(found.map(|d| d.zone), Some(parse_name("com")));
+
+        let address = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53));
+        cache.add_address(
+            &parse_name("example.com"),
+            &parse_name("NS.example.net"),
+            address,
+        );
+        let found = cache
+            .closest_at(&parse_name("www.example.com"), now)
+            .expect("Should find a delegation");
+        assert_eq!(found.zone, parse_name("example.com"));
+        assert_eq!(found.addresses(), vec![address]);
+    }
+
+    #[test]
+    fn delegations_expire() {
+        let cache = DelegationCache::new(10);
+        let now = Instant::now();
+        cache.insert_at(
+            delegation(
+                "com",
+                "a.gtld-servers.net",
+                Some(Ipv4Addr::new(192, 5, 6, 30)),
+            ),
+            60,
+            now,
+        );
+        assert!(cache.closest_at(&parse_name("example.com"), now).is_some());
+        let later = now + Duration::from_secs(60);
+        assert!(cache
+            .closest_at(&parse_name("example.com"), later)
+            .is_none());
+    }
+}
@@ -0,0 +1,87 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+use crate::dns::protocol::DnsFormatError;
+
+// Reasons the resolver gives up on a question without an answer from any authority. These all
+// end up as a SERVFAIL to the client.
+#[derive(Debug, PartialEq)]
+pub enum ResolutionError {
+    // We were asked to resolve something that's already being resolved further up the stack,
+    // e.g. looking up "ns.example.com" in order to find the nameservers for "example.com"
+    Loop(String),
+    // Too many nested lookups (CNAME chases and glueless nameserver lookups)
+    DepthExceeded(usize),
+    // Too many queries sent to authorities on behalf of one client question
+    QueryLimitExceeded(usize),
+    // A CNAME chain went on for more links than we're willing to follow
+    ChainTooLong(usize),
+}
+
+impl fmt::Display for ResolutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResolutionError::Loop(what) => write!(f, "Resolution loop detected on {}", what),
+            ResolutionError::DepthExceeded(depth) => {
+                write!(f, "Resolution exceeded maximum depth of {}", depth)
+            }
+            ResolutionError::QueryLimitExceeded(queries) => {
+                write!(f, "Resolution exceeded limit of {} queries", queries)
+            }
+            ResolutionError::ChainTooLong(links) => {
+                write!(f, "CNAME chain longer than {} links", links)
+            }
+        }
+    }
+}
+
+impl Error for ResolutionError {}
+
+// Ways a single query to an authority can fail. All of these mean the caller should try a
+// different server.
+#[derive(Debug)]
+pub enum QueryError {
+    // No reply arrived from the server, even after retransmitting
+    Timeout(SocketAddr),
+    // Something went wrong with the socket itself
+    Io(io::Error),
+    // The server replied, but we couldn't parse the reply
+    Format(DnsFormatError),
+    // The server echoed our question back with the letters in a different case, so we can't use
+    // case randomisation with it
+    CaseMismatch(SocketAddr),
+    // The server replied over TCP, but not to the question we asked
+    Mismatch(SocketAddr),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::Timeout(server) => write!(f, "Query to {} timed out", server),
+            QueryError::Io(error) => write!(f, "Query failed: {}", error),
+            QueryError::Format(error) => write!(f, "Bad reply: {}", error),
+            QueryError::CaseMismatch(server) => {
+                write!(f, "Reply from {} didn't preserve query name case", server)
+            }
+            QueryError::Mismatch(server) => {
+                write!(f, "Reply from {} didn't match our query", server)
+            }
+        }
+    }
+}
+
+impl Error for QueryError {}
+
+impl From<io::Error> for QueryError {
+    fn from(error: io::Error) -> QueryError {
+        QueryError::Io(error)
+    }
+}
+
+impl From<DnsFormatError> for QueryError {
+    fn from(error: DnsFormatError) -> QueryError {
+        QueryError::Format(error)
+    }
+}
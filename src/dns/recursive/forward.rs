@@ -0,0 +1,555 @@
+// Forwarding to upstream resolvers
+//
+// Not every network lets us talk to authorities directly. In forwarding mode, questions from
+// clients that want recursion are passed along (with RD set) to a pool of upstream resolvers that
+// do the recursion for us, instead of being resolved iteratively. Answers still go through our
+// cache, so we only ask upstream about things we don't already know.
+//
+// Upstreams come and go, so we keep track of which of them are answering. One that fails
+// MAX_FAILURES queries in a row is marked down and only tried once the healthy ones have all
+// failed, until a health check (or a query it gets as a last resort) shows it's back. Which
+// healthy upstream gets asked first depends on the strategy: taking turns, whichever has been
+// answering fastest, or always the first one that's up, in the order they were configured.
+//
+// Forward zones (see the zones module) get upstream pools of their own, which work the same way.
+
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use super::bailiwick;
+use super::cname;
+use super::config;
+use super::errors::QueryError;
+use super::query::{self, QueryOptions, TimedReply};
+use super::zones;
+use super::{answer_from_cache, cache_response, resolve_question};
+use crate::dns::protocol::{DnsClass, DnsPacket, DnsQuestion, DnsRCode, DnsRRType};
+
+// How many queries in a row an upstream can fail before we consider it down
+pub const MAX_FAILURES: u32 = 3;
+
+// How often to check on every upstream
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ForwardStrategy {
+    // Spread queries across the healthy upstreams in turn
+    #[default]
+    RoundRobin,
+    // Ask whichever healthy upstream has been answering fastest
+    LowestLatency,
+    // Ask the first healthy upstream, in the order they were configured
+    StrictOrder,
+}
+
+impl FromStr for ForwardStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ForwardStrategy, String> {
+        match s {
+            "round-robin" => Ok(ForwardStrategy::RoundRobin),
+            "lowest-latency" => Ok(ForwardStrategy::LowestLatency),
+            "strict-order" => Ok(ForwardStrategy::StrictOrder),
+            _ => Err(format!("Unknown forwarding strategy {}", s)),
+        }
+    }
+}
+
+// What we know about one upstream
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamStatus {
+    pub address: SocketAddr,
+    pub healthy: bool,
+    // Smoothed round trip time, once it's answered at least once
+    pub srtt: Option<Duration>,
+    // Failures since it last answered
+    pub failures: u32,
+}
+
+impl fmt::Display for UpstreamStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}, srtt {}, {} failures",
+            self.address,
+            if self.healthy { "up" } else { "down" },
+            match self.srtt {
+                Some(srtt) => format!("{}ms", srtt.as_millis()),
+                None => "unknown".to_owned(),
+            },
+            self.failures
+        )
+    }
+}
+
+pub struct UpstreamPool {
+    strategy: ForwardStrategy,
+    upstreams: Mutex<Vec<UpstreamStatus>>,
+    // Where round robin starts next
+    next: AtomicUsize,
+}
+
+impl UpstreamPool {
+    pub fn new(addresses: &[SocketAddr], strategy: ForwardStrategy) -> UpstreamPool {
+        let upstreams = addresses
+            .iter()
+            .map(|address| UpstreamStatus {
+                address: *address,
+                healthy: true,
+                srtt: None,
+                failures: 0,
+            })
+            .collect();
+        UpstreamPool {
+            strategy,
+            upstreams: Mutex::new(upstreams),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    // The upstreams in the order to try them: the healthy ones as the strategy says, then the
+    // ones that are down, in case they've come back
+    pub fn order(&self) -> Vec<SocketAddr> {
+        let upstreams = self.upstreams.lock().unwrap();
+        let (mut healthy, down): (Vec<&UpstreamStatus>, Vec<&UpstreamStatus>) =
+            upstreams.iter().partition(|upstream| upstream.healthy);
+        match self.strategy {
+            ForwardStrategy::RoundRobin if !healthy.is_empty() => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+                healthy.rotate_left(start);
+            }
+            // Upstreams we haven't timed yet go first, so we find out how fast they are
+            ForwardStrategy::LowestLatency => {
+                healthy.sort_by_key(|upstream| upstream.srtt.unwrap_or_default())
+            }
+            _ => (),
+        }
+        healthy
+            .into_iter()
+            .chain(down)
+            .map(|upstream| upstream.address)
+            .collect()
+    }
+
+    // `upstream` answered, taking `rtt` to do it if we got a clean sample (see TimedReply)
+    pub fn record_success(&self, upstream: SocketAddr, rtt: Option<Duration>) {
+        self.update(upstream, |status| {
+            if !status.healthy {
+                log!("Upstream {} is back up", upstream);
+            }
+            status.healthy = true;
+            status.failures = 0;
+            // The same smoothing TCP (and the infra cache) uses
+            if let Some(rtt) = rtt {
+                status.srtt = Some(match status.srtt {
+                    Some(srtt) => (srtt * 7 + rtt) / 8,
+                    None => rtt,
+                });
+            }
+        });
+    }
+
+    // `upstream` didn't answer, or answered with a failure of its own
+    pub fn record_failure(&self, upstream: SocketAddr) {
+        self.update(upstream, |status| {
+            status.failures += 1;
+            if status.healthy && status.failures >= MAX_FAILURES {
+                log!(
+                    "Upstream {} failed {} times in a row, marking it down",
+                    upstream,
+                    status.failures
+                );
+                status.healthy = false;
+            }
+        });
+    }
+
+    fn update(&self, upstream: SocketAddr, update: impl FnOnce(&mut UpstreamStatus)) {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        if let Some(status) = upstreams.iter_mut().find(|u| u.address == upstream) {
+            update(status);
+        }
+    }
+
+    pub fn statuses(&self) -> Vec<UpstreamStatus> {
+        self.upstreams.lock().unwrap().to_owned()
+    }
+
+    // Send `question` to each upstream in turn until one of them answers it. SERVFAIL and REFUSED
+    // count as failures of the upstream rather than answers, so the next one gets a chance.
+    pub fn forward(
+        &self,
+        question: &DnsQuestion,
+        options: &QueryOptions,
+    ) -> Result<DnsPacket, Box<dyn Error + Send + Sync>> {
+        for upstream in self.order() {
+            log!("Forwarding {:?} to {}", question, upstream);
+            let result = query::timed_query(question, upstream, options, query::global());
+            if let Some(reply) = self.record_reply(upstream, result) {
+                return Ok(reply);
+            }
+        }
+        Err(no_upstream_answered(question))
+    }
+
+    // Count the result of a query to `upstream` for or against it, returning the reply if it's
+    // one to pass on
+    pub fn record_reply(
+        &self,
+        upstream: SocketAddr,
+        result: Result<TimedReply, QueryError>,
+    ) -> Option<DnsPacket> {
+        match result {
+            Ok(TimedReply { reply, .. }) if is_failure(&reply) => {
+                log!("Got {:?} from upstream {}", reply.flags.rcode, upstream);
+                self.record_failure(upstream);
+                None
+            }
+            Ok(TimedReply { reply, rtt }) => {
+                self.record_success(upstream, rtt);
+                Some(reply)
+            }
+            Err(error) => {
+                log!("Query to upstream {} failed: {}", upstream, error);
+                self.record_failure(upstream);
+                None
+            }
+        }
+    }
+
+    // Ask every upstream about the root, to see which of them are answering
+    pub fn check_health(&self, options: &QueryOptions) {
+        let question = DnsQuestion {
+            qname: vec![],
+            qtype: DnsRRType::NS,
+            qclass: DnsClass::IN,
+        };
+        for upstream in self.statuses() {
+            match query::timed_query(&question, upstream.address, options, query::global()) {
+                Ok(timed) if !is_failure(&timed.reply) => {
+                    self.record_success(upstream.address, timed.rtt)
+                }
+                _ => self.record_failure(upstream.address),
+            }
+        }
+    }
+}
+
+pub fn no_upstream_answered(question: &DnsQuestion) -> Box<dyn Error + Send + Sync> {
+    format!("No upstream resolver answered {:?}", question.qname).into()
+}
+
+fn is_failure(reply: &DnsPacket) -> bool {
+    reply.flags.rcode == DnsRCode::ServFail || reply.flags.rcode == DnsRCode::Refused
+}
+
+// The options for queries to upstreams: the same as for authorities, except that we want them to
+// recurse for us
+pub fn upstream_options() -> QueryOptions {
+    QueryOptions {
+        recursion_desired: true,
+        ..config::get().query.to_owned()
+    }
+}
+
+// The upstreams from the configuration, or None if we aren't forwarding
+pub fn pool() -> Option<&'static UpstreamPool> {
+    static POOL: OnceLock<Option<UpstreamPool>> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let config = config::get();
+        if config.forwarders.is_empty() {
+            return None;
+        }
+        Some(UpstreamPool::new(
+            &config.forwarders,
+            config.forward_strategy,
+        ))
+    })
+    .as_ref()
+}
+
+pub fn forwarding_enabled() -> bool {
+    pool().is_some()
+}
+
+// Every pool we forward to: the one for all recursive queries, and the ones for forward zones
+pub fn all_pools() -> Vec<&'static UpstreamPool> {
+    pool().into_iter().chain(zones::global().pools()).collect()
+}
+
+// Answer a question from the cache if we can, and by asking upstream if we can't
+pub fn forward_question(question: &DnsQuestion) -> Result<DnsPacket, Box<dyn Error + Send + Sync>> {
+    let pool = pool().ok_or("No upstream resolvers are configured")?;
+    // A rule for the name's domain overrides the upstreams for everything else
+    if zones::global().find(&question.qname).is_some() {
+        return resolve_question(question);
+    }
+    if let Some(response) = cached_answer(question) {
+        log!("Answering from cache: {:?}", response);
+        return Ok(response);
+    }
+
+    forward_to(pool, None, question)
+}
+
+// A complete answer to `question` from the cache. A cached CNAME is followed through the cache, and
+// if every link of the chain is there along with what's at the end of it (records, or a negative
+// answer), we have the whole answer. Otherwise the question goes upstream.
+pub fn cached_answer(question: &DnsQuestion) -> Option<DnsPacket> {
+    let mut response = answer_from_cache(question)?;
+    let mut chain = cname::follow(&question.qname, question.qtype, &response.answers).ok()?;
+    while !chain.is_complete() && !chain.links.is_empty() {
+        let next = DnsQuestion {
+            qname: chain.target.to_owned(),
+            ..question.to_owned()
+        };
+        let reply = answer_from_cache(&next)?;
+        let rest = cname::follow(&next.qname, next.qtype, &reply.answers).ok()?;
+        if reply.answers.is_empty() {
+            chain.extend(rest).ok()?;
+            response.flags.rcode = reply.flags.rcode;
+            response.nameservers = reply.nameservers;
+            break;
+        }
+        if rest.links.is_empty() && !rest.is_complete() {
+            return None;
+        }
+        chain.extend(rest).ok()?;
+    }
+    response.answers = chain.into_records();
+    Some(response)
+}
+
+// Ask one of the upstreams in `pool` about `question`, caching the answer. `zone` is the forward
+// zone the pool is for, if it's one of those.
+pub fn forward_to(
+    pool: &UpstreamPool,
+    zone: Option<&[String]>,
+    question: &DnsQuestion,
+) -> Result<DnsPacket, Box<dyn Error + Send + Sync>> {
+    let response = pool.forward(question, &upstream_options())?;
+    Ok(forwarded(question, zone, response))
+}
+
+// Cache an upstream's reply and get it ready to pass on. The upstreams for a forward zone are only
+// trusted for names in it, the same as the authorities for a zone, so anything else they send is
+// scrubbed first. If the CNAME chain leaves the zone, the rest of it gets resolved like any other
+// name.
+pub fn forwarded(
+    question: &DnsQuestion,
+    zone: Option<&[String]>,
+    mut response: DnsPacket,
+) -> DnsPacket {
+    if let Some(zone) = zone {
+        bailiwick::scrub_response(&mut response, zone);
+    }
+    cache_response(question, &response);
+    // Whatever the upstream is, we aren't authoritative for anything
+    response.flags.aa_bit = false;
+    response
+}
+
+// Check on the upstreams every HEALTH_CHECK_INTERVAL, on a thread of its own, if we're forwarding
+// anything
+pub fn start_health_checks() {
+    let pools = all_pools();
+    if pools.is_empty() {
+        return;
+    }
+    thread::spawn(move || loop {
+        for pool in &pools {
+            pool.check_health(&upstream_options());
+            for status in pool.statuses() {
+                log!("Upstream {}", status);
+            }
+        }
+        thread::sleep(HEALTH_CHECK_INTERVAL);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::protocol::*;
+    use crate::dns::recursive::cache;
+    use crate::dns::recursive::testing::question;
+
+    use std::net::{Ipv4Addr, UdpSocket};
+
+    fn upstream(last: u8) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::new(192, 0, 2, last).into(), 53)
+    }
+
+    #[test]
+    fn strategies_order_upstreams() {
+        let upstreams = [upstream(1), upstream(2), upstream(3)];
+
+        let pool = UpstreamPool::new(&upstreams, ForwardStrategy::StrictOrder);
+        assert_eq!(pool.order(), upstreams);
+        assert_eq!(pool.order(), upstreams);
+
+        let pool = UpstreamPool::new(&upstreams, ForwardStrategy::RoundRobin);
+        let firsts: Vec<SocketAddr> = (0..3).map(|_| pool.order()[0]).collect();
+        assert_eq!(firsts, upstreams);
+
+        let pool = UpstreamPool::new(&upstreams, ForwardStrategy::LowestLatency);
+        pool.record_success(upstream(1), Some(Duration::from_millis(80)));
+        pool.record_success(upstream(2), Some(Duration::from_millis(10)));
+        pool.record_success(upstream(3), Some(Duration::from_millis(40)));
+        assert_eq!(pool.order(), vec![upstream(2), upstream(3), upstream(1)]);
+    }
+
+    #[test]
+    fn failing_upstreams_go_last_until_they_recover() {
+        let upstreams = [upstream(1), upstream(2)];
+        let pool = UpstreamPool::new(&upstreams, ForwardStrategy::StrictOrder);
+        for _ in 0..MAX_FAILURES - 1 {
+            pool.record_failure(upstream(1));
+        }
+        assert_eq!(pool.order()[0], upstream(1));
+
+        pool.record_failure(upstream(1));
+        assert_eq!(pool.order(), vec![upstream(2), upstream(1)]);
+        assert!(!pool.statuses()[0].healthy);
+
+        pool.record_success(upstream(1), Some(Duration::from_millis(10)));
+        assert_eq!(pool.order(), upstreams);
+    }
+
+    #[test]
+    fn queries_fail_over_to_the_next_upstream() {
+        // The first upstream refuses everything; the second recurses for us
+        let refusing = UdpSocket::bind("127.0.0.1:0").expect("bind test socket");
+        let working = UdpSocket::bind("127.0.0.1:0").expect("bind test socket");
+        let upstreams = [
+            refusing.local_addr().unwrap(),
+            working.local_addr().unwrap(),
+        ];
+        let serve = |socket: UdpSocket, rcode: DnsRCode| {
+            std::thread::spawn(move || {
+                let mut buf = [0; 512];
+                let (amt, client) = socket.recv_from(&mut buf).unwrap();
+                let query = DnsPacket::from_bytes(&buf[..amt]).unwrap();
+                let mut reply = query.to_owned();
+                reply.flags.qr_bit = true;
+                reply.flags.ra_bit = true;
+                reply.flags.rcode = rcode;
+                socket.send_to(&reply.to_bytes(), client).unwrap();
+                query.flags.rd_bit
+            })
+        };
+        let refused = serve(refusing, DnsRCode::Refused);
+        let answered = serve(working, DnsRCode::NXDomain);
+
+        let pool = UpstreamPool::new(&upstreams, ForwardStrategy::StrictOrder);
+        let question = question("forwarded.example", DnsRRType::A);
+        let options = QueryOptions {
+            timeout: Duration::from_secs(2),
+            attempts: 1,
+            recursion_desired: true,
+            ..QueryOptions::default()
+        };
+        let reply = pool
+            .forward(&question, &options)
+            .expect("should get a reply");
+
+        assert_eq!(reply.flags.rcode, DnsRCode::NXDomain);
+        assert!(refused.join().unwrap());
+        assert!(answered.join().unwrap());
+        let statuses = pool.statuses();
+        assert_eq!(statuses[0].failures, 1);
+        assert_eq!(statuses[1].failures, 0);
+        assert!(statuses[1].srtt.is_some());
+    }
+
+    #[test]
+    fn forward_zone_replies_are_scrubbed_before_caching() {
+        // An upstream for corp.test sends an address for a name it has no business answering for
+        let record = |name: &str, rr_type: DnsRRType, record: DnsRecordData| DnsResourceRecord {
+            name: parse_name(name),
+            rr_type,
+            class: DnsClass::IN,
+            ttl: 3600,
+            record,
+        };
+        let question = question("intranet.corp.test", DnsRRType::A);
+        let reply = DnsPacket {
+            id: 1,
+            flags: DnsFlags {
+                qr_bit: true,
+                opcode: DnsOpcode::Query,
+                aa_bit: false,
+                tc_bit: false,
+                rd_bit: true,
+                ra_bit: true,
+                ad_bit: false,
+                cd_bit: false,
+                rcode: DnsRCode::NoError,
+            },
+            questions: vec![question.to_owned()],
+            answers: vec![
+                record(
+                    "intranet.corp.test",
+                    DnsRRType::CNAME,
+                    DnsRecordData::CNAME(parse_name("www.bank.test")),
+                ),
+                record(
+                    "www.bank.test",
+                    DnsRRType::A,
+                    DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 66)),
+                ),
+            ],
+            nameservers: vec![],
+            addl_recs: vec![],
+        };
+
+        let zone = parse_name("corp.test");
+        let reply = forwarded(&question, Some(&zone), reply);
+        assert_eq!(reply.answers.len(), 1);
+        let cache = cache::global();
+        assert!(cache
+            .lookup(&question.qname, DnsRRType::CNAME, DnsClass::IN)
+            .is_some());
+        assert!(cache
+            .lookup(&parse_name("www.bank.test"), DnsRRType::A, DnsClass::IN)
+            .is_none());
+    }
+
+    #[test]
+    fn cached_cname_chains_are_answered_locally() {
+        let cache = cache::global();
+        cache.insert(&[
+            DnsResourceRecord {
+                name: parse_name("www.cached-chain.test"),
+                rr_type: DnsRRType::CNAME,
+                class: DnsClass::IN,
+                ttl: 3600,
+                record: DnsRecordData::CNAME(parse_name("cdn.cached-chain.test")),
+            },
+            DnsResourceRecord {
+                name: parse_name("cdn.cached-chain.test"),
+                rr_type: DnsRRType::A,
+                class: DnsClass::IN,
+                ttl: 3600,
+                record: DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 80)),
+            },
+        ]);
+        let question = question("www.cached-chain.test", DnsRRType::A);
+        let response = cached_answer(&question).expect("whole chain is cached");
+        let types: Vec<DnsRRType> = response.answers.iter().map(|rr| rr.rr_type).collect();
+        assert_eq!(types, vec![DnsRRType::CNAME, DnsRRType::A]);
+
+        // Without the end of the chain, it has to go upstream
+        let question = DnsQuestion {
+            qtype: DnsRRType::AAAA,
+            ..question
+        };
+        assert!(cached_answer(&question).is_none());
+    }
+}
@@ -0,0 +1,459 @@
+// Per-server round trip times
+//
+// Most zones have several nameservers, and they're rarely all equally good choices: some are on
+// the other side of the world, some are overloaded, some are down. We keep track of how quickly
+// each server address has been answering, the way TCP does (RFC 6298): a smoothed RTT, how much
+// it varies, and from those a retransmission timeout (RTO) to use for the next query.
+//
+// When picking a server, anything whose RTO is within RTT_BAND of the best one is fair game, and
+// we choose among those at random; the rest are only used if those fail. This is the same approach
+// Unbound's infrastructure cache takes. Servers we haven't heard from start with a middling RTO,
+// so they get tried now and then and we find out if they're any good, and what we know about a
+// server is forgotten after a while so a server that was slow once gets another chance. There's a
+// limit on how many servers we keep track of; past that, the ones we heard about longest ago go
+// first.
+//
+// We also remember which servers are lame for which zones: delegated to, but refusing to answer
+// for the zone, answering without authority, or referring us back up the tree. Asking them again
+// would just waste a query, so they're left out until LAME_TTL has passed. That list has the same
+// limit on its size, and lameness that's about to run out is the first to be forgotten.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+
+use crate::dns::protocol::lowercase_name;
+
+// The RTO we assume for a server we don't know anything about
+pub const UNKNOWN_RTO: Duration = Duration::from_millis(376);
+
+// Servers whose RTO is within this much of the best server's are treated as equally good
+pub const RTT_BAND: Duration = Duration::from_millis(400);
+
+// Limits on the RTO, however fast or slow a server seems to be
+pub const MIN_RTO: Duration = Duration::from_millis(50);
+pub const MAX_RTO: Duration = Duration::from_secs(12);
+
+// How long we remember a server for after we last heard about it
+pub const HOST_TTL: Duration = Duration::from_secs(15 * 60);
+
+// How many servers we keep stats for, and separately how many (server, zone) pairs we remember as
+// lame
+pub const DEFAULT_CAPACITY: usize = 10000;
+
+// How long a server stays lame for a zone once we've caught it
+pub const LAME_TTL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerStats {
+    // Smoothed round trip time, and its variation
+    pub srtt: Duration,
+    pub rttvar: Duration,
+    // How long to wait for this server before retransmitting
+    pub rto: Duration,
+    pub replies: u64,
+    pub timeouts: u64,
+    updated: Instant,
+}
+
+impl fmt::Display for ServerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "srtt {}ms rttvar {}ms rto {}ms, {} replies, {} timeouts",
+            self.srtt.as_millis(),
+            self.rttvar.as_millis(),
+            self.rto.as_millis(),
+            self.replies,
+            self.timeouts
+        )
+    }
+}
+
+// A server we've given up on for one zone, and why
+#[derive(Clone, Debug, PartialEq)]
+pub struct LameServer {
+    pub server: IpAddr,
+    pub zone: Vec<String>,
+    pub reason: String,
+    expires: Instant,
+}
+
+impl fmt::Display for LameServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} is lame for {:?}: {}",
+            self.server,
+            self.zone.join("."),
+            self.reason
+        )
+    }
+}
+
+pub struct InfraCache {
+    capacity: usize,
+    servers: Mutex<HashMap<IpAddr, ServerStats>>,
+    lame: Mutex<HashMap<(IpAddr, Vec<String>), LameServer>>,
+}
+
+impl InfraCache {
+    pub fn new() -> InfraCache {
+        InfraCache::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> InfraCache {
+        InfraCache {
+            capacity,
+            servers: Mutex::new(HashMap::new()),
+            lame: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Record a reply from `server` that took `rtt` to arrive
+    pub fn record_rtt(&self, server: IpAddr, rtt: Duration) {
+        self.record_rtt_at(server, rtt, Instant::now());
+    }
+
+    pub fn record_rtt_at(&self, server: IpAddr, rtt: Duration, now: Instant) {
+        let mut servers = self.servers.lock().unwrap();
+        let stats = match current(&mut servers, server, now) {
+            Some(stats) => {
+                // RFC 6298 section 2.3, with the usual alpha of 1/8 and beta of 1/4
+                let rttvar = (stats.rttvar * 3 + stats.srtt.abs_diff(rtt)) / 4;
+                let srtt = (stats.srtt * 7 + rtt) / 8;
+                ServerStats {
+                    srtt,
+                    rttvar,
+                    rto: clamp_rto(srtt + rttvar * 4),
+                    replies: stats.replies + 1,
+                    timeouts: stats.timeouts,
+                    updated: now,
+                }
+            }
+            // RFC 6298 section 2.2: the first measurement sets the variation to half of itself
+            None => ServerStats {
+                srtt: rtt,
+                rttvar: rtt / 2,
+                rto: clamp_rto(rtt * 3),
+                replies: 1,
+                timeouts: 0,
+                updated: now,
+            },
+        };
+        make_room(&mut servers, self.capacity, &server, now, |stats| {
+            stats.updated + HOST_TTL
+        });
+        servers.insert(server, stats);
+    }
+
+    // Record that `server` didn't answer at all. Like TCP, we back off, doubling its RTO.
+    pub fn record_timeout(&self, server: IpAddr) {
+        self.record_timeout_at(server, Instant::now());
+    }
+
+    pub fn record_timeout_at(&self, server: IpAddr, now: Instant) {
+        let mut servers = self.servers.lock().unwrap();
+        let stats = match current(&mut servers, server, now) {
+            Some(stats) => ServerStats {
+                rto: clamp_rto(stats.rto * 2),
+                timeouts: stats.timeouts + 1,
+                updated: now,
+                ..stats
+            },
+            None => ServerStats {
+                srtt: UNKNOWN_RTO,
+                rttvar: Duration::ZERO,
+                rto: clamp_rto(UNKNOWN_RTO * 2),
+                replies: 0,
+                timeouts: 1,
+                updated: now,
+            },
+        };
+        make_room(&mut servers, self.capacity, &server, now, |stats| {
+            stats.updated + HOST_TTL
+        });
+        servers.insert(server, stats);
+    }
+
+    // How long to wait for `server` before retransmitting a query to it
+    pub fn rto_at(&self, server: IpAddr, now: Instant) -> Duration {
+        let mut servers = self.servers.lock().unwrap();
+        current(&mut servers, server, now).map_or(UNKNOWN_RTO, |stats| stats.rto)
+    }
+
+    // The RTO for `server`, if we've heard from it (or failed to) recently
+    pub fn measured_rto(&self, server: IpAddr) -> Option<Duration> {
+        let mut servers = self.servers.lock().unwrap();
+        current(&mut servers, server, Instant::now()).map(|stats| stats.rto)
+    }
+
+    // The smoothed RTT for `server`, if we've measured it
+    pub fn srtt(&self, server: IpAddr) -> Option<Duration> {
+        let mut servers = self.servers.lock().unwrap();
+        current(&mut servers, server, Instant::now()).map(|stats| stats.srtt)
+    }
+
+    // Put `addresses` in the order we should try them: the ones within RTT_BAND of the best
+    // server in a random order, then the rest, fastest first
+    pub fn order(&self, addresses: &[IpAddr]) -> Vec<IpAddr> {
+        self.order_at(addresses, Instant::now())
+    }
+
+    pub fn order_at(&self, addresses: &[IpAddr], now: Instant) -> Vec<IpAddr> {
+        let mut ranked: Vec<(IpAddr, Duration)> = addresses
+            .iter()
+            .map(|address| (*address, self.rto_at(*address, now)))
+            .collect();
+        ranked.shuffle(&mut rand::thread_rng());
+        let best = match ranked.iter().map(|(_, rto)| *rto).min() {
+            Some(best) => best,
+            None => return vec![],
+        };
+        let (mut ordered, mut rest): (Vec<_>, Vec<_>) = ranked
+            .into_iter()
+            .partition(|(_, rto)| *rto <= best + RTT_BAND);
+        rest.sort_by_key(|(_, rto)| *rto);
+        ordered.append(&mut rest);
+        ordered.into_iter().map(|(address, _)| address).collect()
+    }
+
+    // Stop asking `server` about `zone` for a while
+    pub fn mark_lame(&self, server: IpAddr, zone: &[String], reason: &str) {
+        self.mark_lame_at(server, zone, reason, Instant::now());
+    }
+
+    pub fn mark_lame_at(&self, server: IpAddr, zone: &[String], reason: &str, now: Instant) {
+        let zone = lowercase_name(zone);
+        let lame = LameServer {
+            server,
+            zone: zone.to_owned(),
+            reason: reason.to_owned(),
+            expires: now + LAME_TTL,
+        };
+        let key = (server, zone);
+        let mut lames = self.lame.lock().unwrap();
+        make_room(&mut lames, self.capacity, &key, now, |lame| lame.expires);
+        lames.insert(key, lame);
+    }
+
+    pub fn is_lame(&self, server: IpAddr, zone: &[String]) -> bool {
+        self.is_lame_at(server, zone, Instant::now())
+    }
+
+    pub fn is_lame_at(&self, server: IpAddr, zone: &[String], now: Instant) -> bool {
+        let mut lame = self.lame.lock().unwrap();
+        let key = (server, lowercase_name(zone));
+        match lame.get(&key) {
+            Some(entry) if now < entry.expires => true,
+            Some(_) => {
+                lame.remove(&key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    // Every server that's currently lame for some zone, for debugging
+    pub fn lame_servers(&self) -> Vec<LameServer> {
+        let now = Instant::now();
+        let mut lame = self.lame.lock().unwrap();
+        lame.retain(|_, entry| now < entry.expires);
+        let mut servers: Vec<LameServer> = lame.values().cloned().collect();
+        servers.sort_by(|a, b| (&a.zone, a.server).cmp(&(&b.zone, b.server)));
+        servers
+    }
+
+    // Everything we currently know, for debugging. Slowest servers last.
+    pub fn stats(&self) -> Vec<(IpAddr, ServerStats)> {
+        let servers = self.servers.lock().unwrap();
+        let mut stats: Vec<(IpAddr, ServerStats)> = servers
+            .iter()
+            .map(|(address, stats)| (*address, stats.to_owned()))
+            .collect();
+        stats.sort_by_key(|(_, stats)| stats.rto);
+        stats
+    }
+}
+
+// The stats for `server`, unless we haven't heard about it in so long that they're stale
+fn current(
+    servers: &mut HashMap<IpAddr, ServerStats>,
+    server: IpAddr,
+    now: Instant,
+) -> Option<ServerStats> {
+    match servers.get(&server) {
+        Some(stats) if now < stats.updated + HOST_TTL => Some(stats.to_owned()),
+        Some(_) => {
+            servers.remove(&server);
+            None
+        }
+        None => None,
+    }
+}
+
+// Get `map` ready for `key` to go in without it growing past `capacity`. Anything that's already
+// expired goes first, and if that isn't enough, whatever would expire soonest.
+fn make_room<K, V>(
+    map: &mut HashMap<K, V>,
+    capacity: usize,
+    key: &K,
+    now: Instant,
+    expires: impl Fn(&V) -> Instant,
+) where
+    K: Clone + Eq + std::hash::Hash,
+{
+    if map.len() < capacity || map.contains_key(key) {
+        return;
+    }
+    map.retain(|_, value| now < expires(value));
+    while map.len() >= capacity {
+        let soonest = match map.iter().min_by_key(|(_, value)| expires(value)) {
+            Some((key, _)) => key.to_owned(),
+            None => break,
+        };
+        map.remove(&soonest);
+    }
+}
+
+fn clamp_rto(rto: Duration) -> Duration {
+    rto.max(MIN_RTO).min(MAX_RTO)
+}
+
+pub fn global() -> &'static InfraCache {
+    static CACHE: OnceLock<InfraCache> = OnceLock::new();
+    CACHE.get_or_init(InfraCache::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    fn server(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, last))
+    }
+
+    #[test]
+    fn rto_follows_measurements() {
+        let cache = InfraCache::new();
+        let now = Instant::now();
+        assert_eq!(cache.rto_at(server(1), now), UNKNOWN_RTO);
+
+        cache.record_rtt_at(server(1), Duration::from_millis(100), now);
+        // srtt 100, rttvar 50
+        assert_eq!(cache.rto_at(server(1), now), Duration::from_millis(300));
+
+        cache.record_rtt_at(server(1), Duration::from_millis(100), now);
+        // srtt 100, rttvar (50 * 3 + 0) / 4 = 37.5
+        assert_eq!(cache.rto_at(server(1), now), Duration::from_micros(250_000));
+
+        cache.record_timeout_at(server(1), now);
+        assert_eq!(cache.rto_at(server(1), now), Duration::from_millis(500));
+
+        // Eventually we forget about it
+        assert_eq!(cache.rto_at(server(1), now + HOST_TTL), UNKNOWN_RTO);
+    }
+
+    #[test]
+    fn fast_servers_are_preferred() {
+        let cache = InfraCache::new();
+        let now = Instant::now();
+        cache.record_rtt_at(server(1), Duration::from_millis(20), now);
+        cache.record_rtt_at(server(2), Duration::from_millis(30), now);
+        cache.record_rtt_at(server(3), Duration::from_millis(2000), now);
+        for _ in 0..5 {
+            cache.record_timeout_at(server(4), now);
+        }
+
+        let mut firsts = Vec::new();
+        for _ in 0..100 {
+            let order = cache.order_at(&[server(4), server(3), server(2), server(1)], now);
+            // The fast ones, in either order, then the slow one, then the one that never answers
+            assert_eq!(&order[2..], &[server(3), server(4)]);
+            firsts.push(order[0]);
+        }
+        // Both of the fast servers get used
+        assert!(firsts.contains(&server(1)));
+        assert!(firsts.contains(&server(2)));
+    }
+
+    #[test]
+    fn unknown_servers_get_probed() {
+        let cache = InfraCache::new();
+        let now = Instant::now();
+        cache.record_rtt_at(server(1), Duration::from_millis(20), now);
+        let firsts: Vec<IpAddr> = (0..100)
+            .map(|_| cache.order_at(&[server(1), server(2)], now)[0])
+            .collect();
+        assert!(firsts.contains(&server(2)));
+    }
+
+    #[test]
+    fn least_recently_heard_from_servers_are_evicted() {
+        let cache = InfraCache::with_capacity(2);
+        let now = Instant::now();
+        cache.record_rtt_at(server(1), Duration::from_millis(100), now);
+        cache.record_rtt_at(
+            server(2),
+            Duration::from_millis(100),
+            now + Duration::from_secs(1),
+        );
+        cache.record_timeout_at(server(1), now + Duration::from_secs(2));
+        cache.record_rtt_at(
+            server(3),
+            Duration::from_millis(100),
+            now + Duration::from_secs(3),
+        );
+
+        let known: Vec<IpAddr> = cache.stats().into_iter().map(|(ip, _)| ip).collect();
+        assert_eq!(known.len(), 2);
+        assert!(known.contains(&server(1)));
+        assert!(known.contains(&server(3)));
+
+        // Stale entries make way before anything current does
+        let later = now + HOST_TTL + Duration::from_secs(2);
+        cache.record_rtt_at(server(4), Duration::from_millis(100), later);
+        let known: Vec<IpAddr> = cache.stats().into_iter().map(|(ip, _)| ip).collect();
+        assert_eq!(known.len(), 2);
+        assert!(known.contains(&server(3)));
+        assert!(known.contains(&server(4)));
+    }
+
+    #[test]
+    fn lameness_is_per_zone_and_expires() {
+        let cache = InfraCache::new();
+        let now = Instant::now();
+        let zone = vec!["Example".to_owned(), "com".to_owned()];
+        cache.mark_lame_at(server(1), &zone, "REFUSED", now);
+
+        assert!(cache.is_lame_at(server(1), &lowercase_name(&zone), now));
+        assert!(!cache.is_lame_at(server(2), &zone, now));
+        assert!(!cache.is_lame_at(server(1), &zone[1..], now));
+        assert!(!cache.is_lame_at(server(1), &zone, now + LAME_TTL));
+        // Checking it after it's run out forgets it
+        assert!(cache.lame.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn lame_servers_are_bounded() {
+        let cache = InfraCache::with_capacity(2);
+        let now = Instant::now();
+        let zone = vec!["example".to_owned(), "com".to_owned()];
+        for last in 1..=3 {
+            let when = now + Duration::from_secs(last as u64);
+            cache.mark_lame_at(server(last), &zone, "REFUSED", when);
+        }
+
+        let later = now + Duration::from_secs(3);
+        assert!(!cache.is_lame_at(server(1), &zone, later));
+        assert!(cache.is_lame_at(server(2), &zone, later));
+        assert!(cache.is_lame_at(server(3), &zone, later));
+        assert_eq!(cache.lame.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,72 @@
+// Resolver metrics
+//
+// Counters for things worth keeping an eye on while the resolver runs. They're plain atomics so
+// any thread can bump them without taking a lock, and `snapshot` gives a consistent-enough copy
+// for logging. If a reporting interval is configured, the counters are logged regularly along with
+// what we know about each authority we've talked to, which of them are lame, and how any upstream
+// resolvers we forward to are doing.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+
+use super::config;
+use super::forward;
+use super::infra;
+
+#[derive(Default)]
+pub struct Metrics {
+    pub priming_attempts: AtomicU64,
+    pub priming_successes: AtomicU64,
+    pub priming_failures: AtomicU64,
+}
+
+// A point-in-time copy of the counters
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    pub priming_attempts: u64,
+    pub priming_successes: u64,
+    pub priming_failures: u64,
+}
+
+impl Metrics {
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            priming_attempts: self.priming_attempts.load(Ordering::Relaxed),
+            priming_successes: self.priming_successes.load(Ordering::Relaxed),
+            priming_failures: self.priming_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub fn global() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::default)
+}
+
+// Log the metrics and server stats every `stats_interval`, if one is configured
+pub fn start_reporting() {
+    let interval = match config::get().stats_interval {
+        Some(interval) => interval,
+        None => return,
+    };
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        println!("Metrics: {:?}", global().snapshot());
+        for (server, stats) in infra::global().stats() {
+            println!("Authority {}: {}", server, stats);
+        }
+        for lame in infra::global().lame_servers() {
+            println!("Lame: {}", lame);
+        }
+        for pool in forward::all_pools() {
+            for status in pool.statuses() {
+                println!("Upstream {}", status);
+            }
+        }
+    });
+}
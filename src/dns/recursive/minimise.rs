@@ -0,0 +1,150 @@
+// QNAME minimisation (RFC 9156)
+//
+// Sending the client's whole question to every server on the way down means the root and TLD
+// servers get to see every name anyone looks up, when all they need to know to refer us onwards
+// is the next label. So instead, we ask each zone's servers about one more label than the zone
+// has ("com" servers get asked about "example.com", not "www.example.com"), and only send the
+// full question once we've found the zone that holds it.
+//
+// Some servers get this wrong, answering NXDOMAIN for a name that has nothing of its own but
+// does have names below it (an "empty non-terminal"). In relaxed mode we don't trust an NXDOMAIN
+// (or an outright failure) for a minimised question, and start over sending the full name. In
+// strict mode, we take the server at its word.
+
+use std::str::FromStr;
+
+use crate::dns::protocol::{DnsQuestion, DnsRRType};
+
+// After this many minimised queries for a single question, just send the full name. Names with
+// lots of labels (reverse lookups in ip6.arpa have 34) would otherwise take a query per label.
+pub const MAX_MINIMISED_QUERIES: usize = 10;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum QnameMinimisation {
+    // Always send the full question
+    Off,
+    // Minimise, but fall back to the full question if a server doesn't cope with it
+    #[default]
+    Relaxed,
+    // Minimise, and believe whatever the servers say about the minimised names
+    Strict,
+}
+
+impl FromStr for QnameMinimisation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<QnameMinimisation, String> {
+        match s {
+            "off" => Ok(QnameMinimisation::Off),
+            "relaxed" => Ok(QnameMinimisation::Relaxed),
+            "strict" => Ok(QnameMinimisation::Strict),
+            _ => Err(format!("Unknown QNAME minimisation mode {}", s)),
+        }
+    }
+}
+
+// How much of one question we've revealed on the way down the tree
+pub struct Minimiser {
+    mode: QnameMinimisation,
+    // How many labels of the name to send in the next minimised question
+    labels: usize,
+    queries: usize,
+}
+
+impl Minimiser {
+    // Start minimising at the servers for `zone`
+    pub fn new(mode: QnameMinimisation, zone: &[String]) -> Minimiser {
+        Minimiser {
+            mode,
+            labels: zone.len() + 1,
+            queries: 0,
+        }
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.mode == QnameMinimisation::Strict
+    }
+
+    // The question to send next, or None if it's time to send the real one. Minimised questions
+    // ask for A records, as RFC 9156 recommends: some servers mishandle NS queries for names that
+    // aren't zone cuts, and an A query looks like any other lookup.
+    pub fn next_question(&mut self, question: &DnsQuestion) -> Option<DnsQuestion> {
+        if self.mode == QnameMinimisation::Off
+            || self.labels >= question.qname.len()
+            || self.queries >= MAX_MINIMISED_QUERIES
+        {
+            return None;
+        }
+        self.queries += 1;
+        Some(DnsQuestion {
+            qname: question.qname[question.qname.len() - self.labels..].to_vec(),
+            qtype: DnsRRType::A,
+            qclass: question.qclass,
+        })
+    }
+
+    // The name we asked about exists, but isn't a zone cut. Reveal another label to the same
+    // servers.
+    pub fn descend(&mut self) {
+        self.labels += 1;
+    }
+
+    // We've been referred to the servers for `zone`
+    pub fn zone_cut(&mut self, zone: &[String]) {
+        self.labels = zone.len() + 1;
+    }
+
+    // Stop minimising this question
+    pub fn give_up(&mut self) {
+        self.mode = QnameMinimisation::Off;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::protocol::*;
+    use crate::dns::recursive::testing::question;
+
+    #[test]
+    fn one_more_label_per_zone() {
+        let question = question("www.dept.example.com", DnsRRType::MX);
+        let mut minimiser = Minimiser::new(QnameMinimisation::Relaxed, &[]);
+        let asked = minimiser.next_question(&question).unwrap();
+        assert_eq!(asked.qname, parse_name("com"));
+        assert_eq!(asked.qtype, DnsRRType::A);
+
+        minimiser.zone_cut(&parse_name("com"));
+        let asked = minimiser.next_question(&question).unwrap();
+        assert_eq!(asked.qname, parse_name("example.com"));
+
+        // dept.example.com isn't a separate zone
+        minimiser.zone_cut(&parse_name("example.com"));
+        minimiser.descend();
+        assert_eq!(minimiser.next_question(&question), None);
+    }
+
+    #[test]
+    fn off_and_given_up_send_the_full_question() {
+        let question = question("www.example.com", DnsRRType::MX);
+        let mut minimiser = Minimiser::new(QnameMinimisation::Off, &[]);
+        assert_eq!(minimiser.next_question(&question), None);
+
+        let mut minimiser = Minimiser::new(QnameMinimisation::Strict, &[]);
+        assert!(minimiser.next_question(&question).is_some());
+        minimiser.give_up();
+        assert_eq!(minimiser.next_question(&question), None);
+    }
+
+    #[test]
+    fn long_names_stop_minimising() {
+        let long = (0..32).map(|i| i.to_string()).collect::<Vec<_>>().join(".") + ".ip6.arpa";
+        let question = question(&long, DnsRRType::MX);
+        let mut minimiser = Minimiser::new(QnameMinimisation::Relaxed, &parse_name("ip6.arpa"));
+        for _ in 0..MAX_MINIMISED_QUERIES {
+            assert!(minimiser.next_question(&question).is_some());
+            minimiser.descend();
+        }
+        assert_eq!(minimiser.next_question(&question), None);
+    }
+}
@@ -1,164 +1,615 @@
 // Recursive resolver functionality
 
+// Print a line about what's going on, unless whoever's sending the queries asked us to keep quiet.
+// `log!(in state; ...)` checks the given QueryState; plain `log!(...)` is for the resolver itself,
+// and checks the one its queries share.
+macro_rules! log {
+    (in $state:expr; $($arg:tt)*) => {
+        if $state.log {
+            println!($($arg)*);
+        }
+    };
+    ($($arg:tt)*) => {
+        if $crate::dns::recursive::query::global().log {
+            println!($($arg)*);
+        }
+    };
+}
+
+mod bailiwick;
+mod cache;
+mod cname;
+mod config;
+mod context;
+mod delegation;
+mod errors;
+mod forward;
+mod infra;
+mod metrics;
+mod minimise;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+mod priming;
+mod query;
 mod root;
+#[cfg(test)]
+mod testing;
+mod zones;
+
+pub use config::{configure, ResolverConfig};
+pub use errors::QueryError;
+pub use forward::{cached_answer, forward_question, forwarding_enabled, start_health_checks};
+pub use metrics::start_reporting as start_stats_reporting;
+pub use priming::start as start_priming;
+pub use query::{query_nameserver, query_with, QueryOptions, QueryState};
+pub use zones::parse_server_address;
+
+use cache::CachedAnswer;
+use cname::Chain;
+use context::ResolutionContext;
+use delegation::{Delegation, Nameserver};
+use errors::ResolutionError;
+use forward::UpstreamPool;
+use minimise::Minimiser;
+use zones::{Action, Rule};
 
 use std::error::Error;
-use std::net::{IpAddr, UdpSocket};
+use std::net::IpAddr;
+
+// Errors from resolving are Send and Sync, so the async resolver can hand them between tasks
+pub type Result<T, E = Box<dyn Error + Send + Sync>> = std::result::Result<T, E>;
 
 use super::protocol::{
-    DnsClass, DnsFlags, DnsOpcode, DnsPacket, DnsQuestion, DnsRCode, DnsRRType, DnsRecordData,
-    DnsResourceRecord,
+    lowercase_name, DnsClass, DnsFlags, DnsOpcode, DnsPacket, DnsQuestion, DnsRCode, DnsRRType,
+    DnsRecordData, DnsResourceRecord,
 };
 
-// Right now this doesn't use caching, doesn't try another nameserver if one fails, and a lot of
-// other little things I'd like to add to it.
-pub fn resolve_question(question: &DnsQuestion) -> Result<DnsPacket, Box<dyn Error>> {
-    // Query the root nameserver
-    let mut ns = root::get_root_nameserver();
-    loop {
-        println!("Asking authority at {:?} question: {:?}", ns, question);
-        let response = query_nameserver(question, ns)?;
-        println!("Got response from authority: {:?}", response);
-        // Check that the response had a nonzero status code, or return an error
-        if response.flags.rcode != DnsRCode::NoError {
-            if response.flags.rcode == DnsRCode::NXDomain {
-                return Ok(response);
-            }
+pub fn resolve_question(question: &DnsQuestion) -> Result<DnsPacket> {
+    let mut context = ResolutionContext::new();
+    resolve_in_context(question, &mut context)
+}
 
-            // TODO(dylan): Handle more errors. We might also get a SERVFAIL or similar, suggesting we
-            // should probably try another server
-            return Err(format!(
-                "Nonzero response code {:?} querying {:?}",
-                response.flags.rcode, ns
-            )
-            .into());
-        };
+// Resolve a question on behalf of some client question, tracked by `context`. Everything that
+// resolves another question (CNAME chasing, nameserver lookups) goes through here so that loops
+// and runaway lookups get caught.
+fn resolve_in_context(
+    question: &DnsQuestion,
+    context: &mut ResolutionContext,
+) -> Result<DnsPacket> {
+    context.enter(question)?;
+    let result = resolve_iteratively(question, context);
+    context.exit();
+    result
+}
 
-        // If we got answers, we move on to answer handling!
-        if response.answers.len() > 0 {
-            return handle_answers(response);
-        }
-
-        // Without an answer, we need to look at the next authority to query. Per RFC 1034, it's
-        // legal for the nameservers section to include the SOA for the nameserver we're talking
-        // to, as well as NS records for nameservers to talk to next. We'll just take the first NS
-        // record returned (this is a common pattern; NS records are often sent in random orders
-        // for this reason).
-        let mut ns_answer = None;
-        for rr in &response.nameservers {
-            if rr.rr_type == DnsRRType::NS {
-                ns_answer = Some(rr);
-                break;
+// The blocking resolver. The decisions along the way are made by the helpers below, which the
+// async resolver (see the nonblocking module) shares; all that's here is the I/O.
+fn resolve_iteratively(
+    question: &DnsQuestion,
+    context: &mut ResolutionContext,
+) -> Result<DnsPacket> {
+    let mut zone = match starting_point(question) {
+        Start::Cached(response) => return handle_answers(response, context),
+        Start::Forward(zone, pool) => {
+            context.count_query()?;
+            let response = forward::forward_to(pool, Some(zone), question)?;
+            if response.answers.is_empty() {
+                return Ok(response);
             }
+            return handle_answers(response, context);
         }
-        if ns_answer == None {
-            // In theory this is disallowed by spec
-            return Err(format!("No error, answer, or nameservers from response").into());
+        Start::Iterate(zone) => zone,
+    };
+    let mut minimiser = Minimiser::new(config::get().qname_minimisation, &zone.zone);
+    loop {
+        let (response, kind) = match minimiser.next_question(question) {
+            Some(minimised) => {
+                let result = query_delegation(&minimised, &zone, context);
+                match minimised_outcome(&minimised, question, result, &mut minimiser)? {
+                    Some(result) => result,
+                    None => continue,
+                }
+            }
+            None => query_delegation(question, &zone, context)?,
+        };
+        match follow_response(question, response, kind, &mut minimiser) {
+            Step::Answer(response) => return handle_answers(response, context),
+            Step::Done(response) => return Ok(response),
+            Step::Referral(next_zone) => zone = next_zone,
         }
+    }
+}
 
-        // We may have a glue record for this nameserver; use it if we find it
-        let glue_record_ip = find_glue_record_for_ns(ns_answer.unwrap(), &response.addl_recs);
-        match glue_record_ip {
-            None => {
-                ns = get_nameserver_address(ns_answer.unwrap())?;
+// Ask the servers for `zone` our question, in the order `plan_servers` puts them in, until one of
+// them gives us a usable response. We only give up once every server's been tried.
+fn query_delegation(
+    question: &DnsQuestion,
+    zone: &Delegation,
+    context: &mut ResolutionContext,
+) -> Result<(DnsPacket, ResponseKind)> {
+    let servers = plan_servers(zone);
+    for address in servers.addresses {
+        if let Some(result) = try_nameserver(question, address, &zone.zone, context)? {
+            return Ok(result);
+        }
+    }
+    for ns_name in &servers.unaddressed {
+        let addresses = match get_nameserver_address(ns_name, context) {
+            Ok(addresses) => addresses,
+            // Hitting one of our limits means we're done with this question entirely, but any
+            // other failure just means we should move on to the next server
+            Err(error) if is_limit_error(error.as_ref()) => return Err(error),
+            Err(error) => {
+                log!(
+                    "Couldn't find address for nameserver {:?}: {}",
+                    ns_name,
+                    error
+                );
+                continue;
             }
-            Some(ip) => {
-                ns = ip;
+        };
+        for address in learned_addresses(zone, ns_name, addresses) {
+            if let Some(result) = try_nameserver(question, address, &zone.zone, context)? {
+                return Ok(result);
             }
         }
     }
+    Err(no_usable_response(zone))
 }
 
-fn handle_answers(mut response: DnsPacket) -> Result<DnsPacket, Box<dyn Error>> {
-    // If our answers have a CNAME, we have to (recursively) go lookup the CNAME too. If it has
-    // multiple CNAMEs, or a CNAME and other records, it's breaking the spec; we'll just ignore
-    // that case right now, though we might want to return a FORMERR or something?
-    if response.answers.len() == 1 {
-        match &response.answers[0].record {
-            DnsRecordData::CNAME(labels) => {
-                // We're asking a question for the canonical name, now. Class and type stay the
-                // same.
-                let question = DnsQuestion {
-                    qname: labels.to_owned(),
-                    // It should be safe to assume there's one and only one question here, though
-                    // we may want to assert it, since a bad server could strip questions or
-                    // something else weird.
-                    qclass: response.questions[0].qclass,
-                    qtype: response.questions[0].qtype,
-                };
-                // Note that resolve_question calls this function, so if our reply has another
-                // CNAME in it, that will be handled before it's returned back to us
-                let reply = resolve_question(&question)?;
-
-                // We add the answers, nameservers, and additional records from the CNAME reply to
-                // our original answer, but we don't change the question
-                response.answers.extend(reply.answers);
-                response.nameservers.extend(reply.nameservers);
-                response.addl_recs.extend(reply.addl_recs);
-            }
-            _ => (),
+// Send our question to one of the nameservers for `zone`. Returns None if the server failed to give
+// us something we can use, so the caller can try the next one.
+fn try_nameserver(
+    question: &DnsQuestion,
+    ns: IpAddr,
+    zone: &[String],
+    context: &mut ResolutionContext,
+) -> Result<Option<(DnsPacket, ResponseKind)>> {
+    log!("Asking authority at {:?} question: {:?}", ns, question);
+    context.count_query()?;
+    let result = query::query_authority(question, ns, &config::get().query);
+    Ok(usable_response(ns, zone, result))
+}
+
+// Turn a response with answers into the one we'll give the client, looking up the rest of its CNAME
+// chain if we need to (see `chain_from_answers`)
+fn handle_answers(response: DnsPacket, context: &mut ResolutionContext) -> Result<DnsPacket> {
+    let (chain, next) = chain_from_answers(&response)?;
+    let rest = match next {
+        // Note that resolve_in_context calls this function, so the reply has already had its own
+        // part of the chain followed
+        Some(next) => {
+            let reply = resolve_in_context(&next, context)?;
+            Some((next, reply))
+        }
+        None => None,
+    };
+    finish_answers(response, chain, rest)
+}
+
+// Look up the addresses of a nameserver we were referred to without glue. This can loop if we're
+// asked to talk to, for instance, "ns.example.com" to find out where "example.com" is; `context`
+// is how we notice that.
+fn get_nameserver_address(
+    ns_name: &[String],
+    context: &mut ResolutionContext,
+) -> Result<Vec<IpAddr>> {
+    context.enter_nameserver(ns_name)?;
+    let mut lookups = Vec::new();
+    for question in nameserver_questions(ns_name) {
+        let result = resolve_in_context(&question, context);
+        let limited = matches!(&result, Err(error) if is_limit_error(error.as_ref()));
+        lookups.push((question.qtype, result));
+        if limited {
+            break;
         }
     }
-    Ok(response)
+    context.exit_nameserver();
+    addresses_from_lookups(lookups)
 }
 
-fn find_glue_record_for_ns(
-    ns: &DnsResourceRecord,
-    records: &Vec<DnsResourceRecord>,
-) -> Option<IpAddr> {
-    let ns_name = match &ns.record {
-        DnsRecordData::NS(name) => name,
-        _ => panic!("NS record data is not stored properly"),
+// Where resolution of a question starts
+enum Start {
+    // We already know the answer, though it may be a CNAME that needs chasing
+    Cached(DnsPacket),
+    // The name's in this forward zone, so its resolvers get asked instead
+    Forward(&'static [String], &'static UpstreamPool),
+    // Iterate down from the servers for this zone
+    Iterate(Delegation),
+}
+
+fn starting_point(question: &DnsQuestion) -> Start {
+    // Before we touch the network, see if we already know the answer
+    if let Some(response) = answer_from_cache(question) {
+        log!("Answering from cache: {:?}", response);
+        return Start::Cached(response);
+    }
+
+    // Names in a forward zone go to that zone's resolvers instead of being resolved here
+    let rule = zones::global().find(&question.qname);
+    let stub = match rule {
+        Some(Rule {
+            zone,
+            action: Action::Forward(pool),
+        }) => {
+            log!("Forwarding {:?} for zone {:?}", question, zone);
+            return Start::Forward(zone, pool);
+        }
+        Some(Rule {
+            action: Action::Stub(stub),
+            ..
+        }) => Some(stub),
+        None => None,
     };
 
-    for rr in records {
-        if &rr.name == ns_name {
-            match rr.record {
-                DnsRecordData::A(ip_addr) => return Some(IpAddr::V4(ip_addr)),
-                _ => (),
+    // Start from the closest zone cut we know about, or the root if we don't know any. Names in a
+    // stub zone start at its servers instead, unless we've already been referred further down.
+    Start::Iterate(match delegation::global().closest(&question.qname) {
+        Some(delegation) if stub.is_none_or(|stub| delegation.zone.len() > stub.zone.len()) => {
+            log!("Starting from cached delegation for {:?}", delegation.zone);
+            delegation
+        }
+        _ => match stub {
+            Some(stub) => {
+                log!("Starting from stub zone {:?}", stub.zone);
+                stub.to_owned()
             }
+            None => root::get_root_delegation(),
+        },
+    })
+}
+
+// What to do after an authority's response to the full question
+enum Step {
+    // Records for us, which may need CNAME chasing (see `handle_answers`)
+    Answer(DnsPacket),
+    // A negative answer that can go straight back to the client
+    Done(DnsPacket),
+    // The servers to ask next
+    Referral(Delegation),
+}
+
+fn follow_response(
+    question: &DnsQuestion,
+    response: DnsPacket,
+    kind: ResponseKind,
+    minimiser: &mut Minimiser,
+) -> Step {
+    match kind {
+        ResponseKind::Answer => {
+            cache_response(question, &response);
+            Step::Answer(response)
+        }
+        // Either the name doesn't exist, or it exists without any records of the type we asked
+        // for. Both are perfectly good answers to hand back to the client.
+        ResponseKind::NXDomain | ResponseKind::NoData => {
+            cache_response(question, &response);
+            Step::Done(response)
+        }
+        ResponseKind::Error | ResponseKind::Invalid => {
+            unreachable!("query_delegation only returns usable responses")
+        }
+        ResponseKind::Referral => {
+            // Per RFC 1034, it's legal for the nameservers section to include the SOA for the
+            // nameserver we're talking to, as well as NS records for nameservers to talk to next.
+            // We hold on to every NS record for the next zone, along with all of their glue, so
+            // there's somewhere else to go if the first server doesn't work out. We also remember
+            // the zone cut, so later questions for names in the zone can start here.
+            let (next_zone, ttl) = delegation_from_referral(&response);
+            delegation::global().insert(next_zone.to_owned(), ttl);
+            minimiser.zone_cut(&next_zone.zone);
+            Step::Referral(next_zone)
         }
     }
-    return None;
 }
 
-fn get_nameserver_address(ns: &DnsResourceRecord) -> Result<IpAddr, Box<dyn Error>> {
-    // TODO(dylan): We should detect an infinite loop being caused by a missing glue record. This
-    // can happen if we're asked to talk to, for instance, "ns.example.com" to find out where
-    // "example.com" is. We'll keep repeating the same NS lookup over and over.
-    let ns_name = match &ns.record {
-        DnsRecordData::NS(name) => name,
-        _ => panic!("NS record data is not stored properly"),
-    };
-    let question = DnsQuestion {
-        // Again, label copying seems inefficient
-        qname: ns_name.to_owned(),
-        // Again, hardcoding IPv4
-        qtype: DnsRRType::A,
-        qclass: DnsClass::IN,
+// Cache what a response tells us about `question`, whether it came from an authority or an
+// upstream. Of the answers, only the ones `cname::follow` accepts go in: the chain from the name
+// we asked about, along with any DNAMEs it came from, and the records at the end of it. Anything
+// else, like a CNAME that contradicts the DNAME it was supposedly synthesized from, stays out.
+//
+// If the chain doesn't reach the records we asked for, the rcode and SOA are about the name at the
+// end of it (RFC 2308 section 2.1), so that's the name the negative answer is cached for; the
+// aliases leading there exist, and are cached like any other records. Negative answers without an
+// SOA aren't cached at all, since RFC 2308 says we don't know how long they're true for.
+fn cache_response(question: &DnsQuestion, response: &DnsPacket) {
+    let rcode = &response.flags.rcode;
+    if *rcode != DnsRCode::NoError && *rcode != DnsRCode::NXDomain {
+        return;
+    }
+    let chain = match cname::follow(&question.qname, question.qtype, &response.answers) {
+        Ok(chain) => chain,
+        Err(error) => {
+            log!("Not caching answers for {:?}: {}", question.qname, error);
+            return;
+        }
     };
-    // XXX this is definitely not a production server without loop detection
-    let result = resolve_question(&question)?;
-    for answer in &result.answers {
-        if answer.rr_type == DnsRRType::A {
-            match answer.record {
-                DnsRecordData::A(addr) => return Ok(IpAddr::V4(addr)),
-                _ => continue,
+
+    let cache = cache::global();
+    let complete = chain.is_complete();
+    let target = chain.target.to_owned();
+    cache.insert(&chain.into_records());
+    if let Some(soa) = find_soa(&response.nameservers) {
+        if *rcode == DnsRCode::NXDomain {
+            cache.insert_nxdomain(&target, question.qclass, soa);
+        } else if !complete {
+            cache.insert_nodata(&target, question.qtype, question.qclass, soa);
+        }
+    }
+}
+
+// Deal with the result of sending a minimised version of `question`. What we're after is a
+// referral, which is handed back to be followed like any other. Anything else is dealt with here,
+// returning None to have the caller carry on with the next question the minimiser comes up with.
+fn minimised_outcome(
+    minimised: &DnsQuestion,
+    question: &DnsQuestion,
+    result: Result<(DnsPacket, ResponseKind)>,
+    minimiser: &mut Minimiser,
+) -> Result<Option<(DnsPacket, ResponseKind)>> {
+    let (mut response, kind) = match result {
+        Ok(result) => result,
+        Err(error) => {
+            if is_limit_error(error.as_ref()) || minimiser.is_strict() {
+                return Err(error);
             }
+            log!(
+                "Minimised query for {:?} failed ({}), sending the full name",
+                minimised.qname,
+                error
+            );
+            minimiser.give_up();
+            return Ok(None);
+        }
+    };
+    match kind {
+        ResponseKind::Referral => Ok(Some((response, kind))),
+        // The name exists (and so might have names below it), but the same servers are
+        // authoritative for it. Try the next label down.
+        ResponseKind::Answer | ResponseKind::NoData => {
+            minimiser.descend();
+            Ok(None)
         }
+        // If the minimised name doesn't exist, then neither does anything below it, including the
+        // name the client asked about (RFC 8020)
+        ResponseKind::NXDomain if minimiser.is_strict() => {
+            response.questions = vec![question.to_owned()];
+            Ok(Some((response, kind)))
+        }
+        ResponseKind::NXDomain => {
+            log!(
+                "Got NXDOMAIN for minimised name {:?}, sending the full name",
+                minimised.qname
+            );
+            minimiser.give_up();
+            Ok(None)
+        }
+        ResponseKind::Error | ResponseKind::Invalid => {
+            unreachable!("query_delegation only returns usable responses")
+        }
+    }
+}
+
+// The servers for a zone, in the order to ask them
+struct Servers {
+    // Addresses we can send to right away, fastest first
+    addresses: Vec<IpAddr>,
+    // The servers we don't have any addresses for (at least, not in an address family we're
+    // using), which we'll have to look up first
+    unaddressed: Vec<Vec<String>>,
+}
+
+// The fastest servers get asked first (except for the roots, which are asked in the order the root
+// selection put them in), and servers we know to be lame for the zone aren't asked at all, unless
+// they're all we've got. Servers we already have addresses for are tried before the ones we'd have
+// to go look up.
+fn plan_servers(zone: &Delegation) -> Servers {
+    let preference = config::get().ip_preference;
+    let infra = infra::global();
+    let addresses: Vec<IpAddr> = zone
+        .addresses()
+        .into_iter()
+        .filter(|address| preference.allows(address))
+        .collect();
+    let mut usable: Vec<IpAddr> = addresses
+        .iter()
+        .copied()
+        .filter(|address| !infra.is_lame(*address, &zone.zone))
+        .collect();
+    let unaddressed: Vec<Vec<String>> = zone
+        .nameservers
+        .iter()
+        .filter(|ns| {
+            !ns.addresses
+                .iter()
+                .any(|address| preference.allows(address))
+        })
+        .map(|ns| ns.name.to_owned())
+        .collect();
+    if usable.is_empty() && unaddressed.is_empty() && !addresses.is_empty() {
+        // Maybe they've been fixed since. Either way, it's this or SERVFAIL.
+        log!("Every server for {:?} is lame, asking anyway", zone.zone);
+        usable = addresses;
+    }
+    // The roots come already ordered by the configured root selection (see root::RootSelection)
+    if !zone.zone.is_empty() {
+        usable = infra.order(&usable);
     }
-    return Err(format!(
-        "Got result without A records when doing nameserver lookup: {:?}",
-        result
+    Servers {
+        addresses: usable,
+        unaddressed,
+    }
+}
+
+// Remember the addresses we looked up for one of a zone's nameservers, and return the ones worth
+// asking
+fn learned_addresses(zone: &Delegation, ns_name: &[String], addresses: Vec<IpAddr>) -> Vec<IpAddr> {
+    let infra = infra::global();
+    addresses
+        .into_iter()
+        .inspect(|address| delegation::global().add_address(&zone.zone, ns_name, *address))
+        .filter(|address| !infra.is_lame(*address, &zone.zone))
+        .collect()
+}
+
+fn no_usable_response(zone: &Delegation) -> Box<dyn Error + Send + Sync> {
+    format!(
+        "No nameserver for zone {:?} gave a usable response",
+        zone.zone
     )
-    .into());
+    .into()
+}
+
+// Look at what came back from asking `ns`, one of the servers for `zone`, and return it if it's
+// usable. Servers that can't be reached, send back garbage, are lame, or answer with an error rcode
+// (SERVFAIL, REFUSED, ...) give us None, so the next server can be tried.
+fn usable_response(
+    ns: IpAddr,
+    zone: &[String],
+    result: Result<DnsPacket, QueryError>,
+) -> Option<(DnsPacket, ResponseKind)> {
+    let mut response = match result {
+        Ok(response) => response,
+        Err(error) => {
+            log!("Query to {:?} failed: {}", ns, error);
+            return None;
+        }
+    };
+    log!("Got response from authority: {:?}", response);
+
+    if let Some(reason) = lame_reason(&response, zone) {
+        log!("{:?} is lame for {:?}: {}", ns, zone, reason);
+        infra::global().mark_lame(ns, zone, reason);
+        return None;
+    }
+
+    // Throw away anything the server isn't an authority for before it can get anywhere near the
+    // caches
+    bailiwick::scrub_response(&mut response, zone);
+    let kind = classify_response(&response);
+    match kind {
+        ResponseKind::Error => {
+            log!(
+                "Got {:?} from {:?}, trying another server",
+                response.flags.rcode,
+                ns
+            );
+            None
+        }
+        ResponseKind::Invalid => {
+            // In theory this is disallowed by spec
+            log!("No error, answer, or nameservers from {:?}", ns);
+            None
+        }
+        _ => Some((response, kind)),
+    }
+}
+
+// The different kinds of response an authority can send back to us
+#[derive(PartialEq, Debug)]
+enum ResponseKind {
+    // The answer section has records for us (possibly a CNAME we'll need to chase)
+    Answer,
+    // We've been pointed at the nameservers for a zone further down the tree
+    Referral,
+    // The name we asked about doesn't exist
+    NXDomain,
+    // The name exists, but has no records of the type we asked for
+    NoData,
+    // Some other nonzero rcode, e.g. SERVFAIL or REFUSED
+    Error,
+    // A NOERROR response that doesn't fit any of the above
+    Invalid,
+}
+
+// Work out what an authority's response is telling us. NODATA is the subtle one: RFC 2308 section
+// 2.2 allows it to come back with an SOA in the authority section (optionally alongside the
+// zone's NS records), or with an empty authority section entirely. What sets it apart from a
+// referral is that it's authoritative; a referral never has the AA bit set.
+fn classify_response(response: &DnsPacket) -> ResponseKind {
+    match response.flags.rcode {
+        DnsRCode::NoError => (),
+        DnsRCode::NXDomain => return ResponseKind::NXDomain,
+        _ => return ResponseKind::Error,
+    }
+
+    if !response.answers.is_empty() {
+        return ResponseKind::Answer;
+    }
+    // Only the zone's own servers can tell us there's nothing there. An SOA from anyone else (a
+    // resolver, or a server with a stale copy of the zone) is no grounds for a negative answer.
+    if response.flags.aa_bit
+        && (find_soa(&response.nameservers).is_some() || response.nameservers.is_empty())
+    {
+        return ResponseKind::NoData;
+    }
+    if response
+        .nameservers
+        .iter()
+        .any(|rr| rr.rr_type == DnsRRType::NS)
+    {
+        return ResponseKind::Referral;
+    }
+    ResponseKind::Invalid
+}
+
+// Is this response a sign that the server isn't really serving `zone`, even though it was
+// delegated to? That's the case if it refuses to answer, answers without the AA bit (it's probably
+// a resolver, or has the zone cached from somewhere), or refers us to somewhere that isn't further
+// down the tree.
+fn lame_reason(response: &DnsPacket, zone: &[String]) -> Option<&'static str> {
+    match response.flags.rcode {
+        DnsRCode::Refused => return Some("refused the query"),
+        DnsRCode::NoError | DnsRCode::NXDomain => (),
+        _ => return None,
+    }
+    if response.flags.aa_bit {
+        return None;
+    }
+    if !response.answers.is_empty() || response.flags.rcode == DnsRCode::NXDomain {
+        return Some("answered without authority");
+    }
+    let mut referrals = response
+        .nameservers
+        .iter()
+        .filter(|rr| rr.rr_type == DnsRRType::NS)
+        .peekable();
+    if referrals.peek().is_some()
+        && referrals
+            .all(|rr| rr.name.len() <= zone.len() || !bailiwick::in_bailiwick(&rr.name, zone))
+    {
+        return Some("referred us back up the tree");
+    }
+    None
 }
 
-// Sends a query to an authoritative nameserver
-fn query_nameserver(question: &DnsQuestion, ns: IpAddr) -> Result<DnsPacket, Box<dyn Error>> {
-    // Construct the query
+// Build a response out of the cache, if we have either the RRset the question is asking for, a
+// negative answer for it, or a CNAME for the name in question. In the CNAME case,
+// `handle_answers` will chase the alias (which itself will hopefully be cached).
+fn answer_from_cache(question: &DnsQuestion) -> Option<DnsPacket> {
+    let cache = cache::global();
+    let cached = cache
+        .lookup(&question.qname, question.qtype, question.qclass)
+        .or_else(|| {
+            if question.qtype == DnsRRType::CNAME {
+                return None;
+            }
+            match cache.lookup(&question.qname, DnsRRType::CNAME, question.qclass) {
+                Some(CachedAnswer::Records(records)) => Some(CachedAnswer::Records(records)),
+                _ => None,
+            }
+        })?;
+
+    // Negative answers are sent back with the zone's SOA in the authority section, the same way
+    // the authority sent them to us
+    let (rcode, answers, nameservers) = match cached {
+        CachedAnswer::Records(records) => (DnsRCode::NoError, records, vec![]),
+        CachedAnswer::NoData(soa) => (DnsRCode::NoError, vec![], vec![soa]),
+        CachedAnswer::NXDomain(soa) => (DnsRCode::NXDomain, vec![], vec![soa]),
+    };
+
     let flags = DnsFlags {
-        qr_bit: false,
+        qr_bit: true,
         opcode: DnsOpcode::Query,
         aa_bit: false,
         tc_bit: false,
@@ -166,52 +617,512 @@ fn query_nameserver(question: &DnsQuestion, ns: IpAddr) -> Result<DnsPacket, Box
         ra_bit: false,
         ad_bit: false,
         cd_bit: false,
-        rcode: DnsRCode::NoError,
+        rcode,
     };
-    let packet = DnsPacket {
-        // TODO real arbitrary ID instead of just hardcoded one
-        id: 42,
+    Some(DnsPacket {
+        // The caller is responsible for setting the ID to match the client's query
+        id: 0,
         flags,
-        // TODO is copying the question the right thing to do here? We don't _really_ need another
-        // object, we could potentially refactor packet to write bytes from references. qname is a
-        // string vector, so this is a non-trivial copy.
         questions: vec![question.to_owned()],
-        answers: vec![],
-        nameservers: vec![],
+        answers,
+        nameservers,
         addl_recs: vec![],
+    })
+}
+
+// The first step in turning a response with answers into the one we'll give the client. If the
+// answers are (or start with) a CNAME chain, we follow it as far as the records we already have
+// go. If that isn't all the way, this also returns the question to ask to get the rest of it.
+fn chain_from_answers(response: &DnsPacket) -> Result<(Chain, Option<DnsQuestion>)> {
+    // It should be safe to assume there's one and only one question here, though we may want to
+    // assert it, since a bad server could strip questions or something else weird.
+    let question = &response.questions[0];
+    let chain = cname::follow(&question.qname, question.qtype, &response.answers)?;
+    let next = if !chain.is_complete() && !chain.links.is_empty() {
+        // We're asking a question for the canonical name, now. Class and type stay the same.
+        Some(DnsQuestion {
+            qname: chain.target.to_owned(),
+            ..question.to_owned()
+        })
+    } else {
+        None
     };
+    Ok((chain, next))
+}
+
+// Put together the answer from the chain, along with the reply to the question for the rest of it
+// if there was one. The client gets the whole chain followed by the records at the end of it, and
+// none of the authority or additional records the servers sent along the way. If the chain ends in
+// a name that doesn't exist or has no records of the type asked for, that's what the response
+// says, with the SOA from the last zone so the client can cache it.
+fn finish_answers(
+    mut response: DnsPacket,
+    mut chain: Chain,
+    rest: Option<(DnsQuestion, DnsPacket)>,
+) -> Result<DnsPacket> {
+    let mut nameservers = vec![];
+    if let Some((next, reply)) = rest {
+        chain.extend(cname::follow(&next.qname, next.qtype, &reply.answers)?)?;
+        response.flags.rcode = reply.flags.rcode;
+        if !chain.is_complete() {
+            nameservers = reply
+                .nameservers
+                .into_iter()
+                .filter(|rr| rr.rr_type == DnsRRType::SOA)
+                .collect();
+        }
+    }
+
+    response.answers = chain.into_records();
+    response.nameservers = nameservers;
+    response.addl_recs = vec![];
+    Ok(response)
+}
 
-    // Send the query
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
-    socket.connect((ns, 53))?;
-    socket.send(&packet.to_bytes())?;
-    let mut buf = [0; 2048];
-    let amt = socket.recv(&mut buf)?;
+// Build the delegation from a referral response: every NS record for the zone in the authority
+// section, along with any glue for them from the additional section. Also returns how long the
+// delegation is good for, which is the lowest TTL of all the records involved.
+fn delegation_from_referral(response: &DnsPacket) -> (Delegation, u32) {
+    // `classify_response` already checked that a referral has at least one NS record
+    let zone = &response
+        .nameservers
+        .iter()
+        .find(|rr| rr.rr_type == DnsRRType::NS)
+        .unwrap()
+        .name;
+    let zone_key = lowercase_name(zone);
 
-    // Process the reply
-    let reply = DnsPacket::from_bytes(&buf[..amt])?;
+    let mut ttl = u32::MAX;
+    let mut nameservers = Vec::new();
+    for rr in &response.nameservers {
+        if rr.rr_type != DnsRRType::NS || lowercase_name(&rr.name) != zone_key {
+            continue;
+        }
+        let ns_name = match &rr.record {
+            DnsRecordData::NS(name) => name,
+            _ => continue,
+        };
+        ttl = ttl.min(rr.ttl);
 
-    Ok(reply)
+        let mut addresses = Vec::new();
+        for glue in find_glue_records_for_ns(ns_name, &response.addl_recs) {
+            if let Some(address) = address_from_record(glue) {
+                addresses.push(address);
+                ttl = ttl.min(glue.ttl);
+            }
+        }
+        nameservers.push(Nameserver {
+            name: ns_name.to_owned(),
+            addresses,
+        });
+    }
+
+    let delegation = Delegation {
+        zone: zone.to_owned(),
+        nameservers,
+    };
+    (delegation, ttl)
+}
+
+fn find_soa(records: &[DnsResourceRecord]) -> Option<&DnsResourceRecord> {
+    records.iter().find(|rr| rr.rr_type == DnsRRType::SOA)
+}
+
+fn find_glue_records_for_ns<'a>(
+    ns_name: &[String],
+    records: &'a [DnsResourceRecord],
+) -> Vec<&'a DnsResourceRecord> {
+    let ns_key = lowercase_name(ns_name);
+    records
+        .iter()
+        .filter(|rr| lowercase_name(&rr.name) == ns_key)
+        .collect()
+}
+
+// The questions to ask for a nameserver's addresses: A records, AAAA records, or both, depending on
+// which address families we're configured to use
+fn nameserver_questions(ns_name: &[String]) -> Vec<DnsQuestion> {
+    config::get()
+        .ip_preference
+        .address_types()
+        .into_iter()
+        .map(|qtype| DnsQuestion {
+            // Again, label copying seems inefficient
+            qname: ns_name.to_owned(),
+            qtype,
+            qclass: DnsClass::IN,
+        })
+        .collect()
+}
+
+// The addresses from the lookups for a nameserver. We only fail if none of them give us an
+// address, or one of them ran into our limits.
+fn addresses_from_lookups(lookups: Vec<(DnsRRType, Result<DnsPacket>)>) -> Result<Vec<IpAddr>> {
+    let mut addresses = Vec::new();
+    let mut failure = None;
+    for (qtype, result) in lookups {
+        match result {
+            Ok(result) => {
+                let found: Vec<IpAddr> = result
+                    .answers
+                    .iter()
+                    .filter_map(address_from_record)
+                    .collect();
+                if found.is_empty() {
+                    failure = Some(
+                        format!(
+                            "Got result without {:?} records when doing nameserver lookup: {:?}",
+                            qtype, result
+                        )
+                        .into(),
+                    );
+                }
+                addresses.extend(found);
+            }
+            Err(error) if is_limit_error(error.as_ref()) => return Err(error),
+            Err(error) => failure = Some(error),
+        }
+    }
+
+    match failure {
+        Some(error) if addresses.is_empty() => Err(error),
+        _ => Ok(addresses),
+    }
+}
+
+// The address in an A or AAAA record
+fn address_from_record(rr: &DnsResourceRecord) -> Option<IpAddr> {
+    match rr.record {
+        DnsRecordData::A(address) => Some(IpAddr::V4(address)),
+        DnsRecordData::AAAA(address) => Some(IpAddr::V6(address)),
+        _ => None,
+    }
+}
+
+// Is this one of the errors that means we should stop working on the client's question entirely?
+fn is_limit_error(error: &(dyn Error + 'static)) -> bool {
+    matches!(
+        error.downcast_ref(),
+        Some(ResolutionError::DepthExceeded(_)) | Some(ResolutionError::QueryLimitExceeded(_))
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dns::protocol::parse_name;
+    use crate::dns::recursive::testing::question;
+
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    fn response(aa_bit: bool, rcode: DnsRCode, nameservers: Vec<DnsResourceRecord>) -> DnsPacket {
+        DnsPacket {
+            id: 42,
+            flags: DnsFlags {
+                qr_bit: true,
+                opcode: DnsOpcode::Query,
+                aa_bit,
+                tc_bit: false,
+                rd_bit: false,
+                ra_bit: false,
+                ad_bit: false,
+                cd_bit: false,
+                rcode,
+            },
+            questions: vec![question("www.example.com", DnsRRType::AAAA)],
+            answers: vec![],
+            nameservers,
+            addl_recs: vec![],
+        }
+    }
+
+    fn soa_record() -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: parse_name("example.com"),
+            rr_type: DnsRRType::SOA,
+            class: DnsClass::IN,
+            ttl: 3600,
+            record: DnsRecordData::SOA {
+                mname: parse_name("ns1.example.com"),
+                rname: parse_name("hostmaster.example.com"),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            },
+        }
+    }
+
+    fn ns_record(zone: &str, ns: &str) -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: parse_name(zone),
+            rr_type: DnsRRType::NS,
+            class: DnsClass::IN,
+            ttl: 172800,
+            record: DnsRecordData::NS(parse_name(ns)),
+        }
+    }
+
+    #[test]
+    fn nodata_is_recognised() {
+        // SOA only, which is what you get asking for AAAA on an IPv4-only host
+        let packet = response(true, DnsRCode::NoError, vec![soa_record()]);
+        assert_eq!(classify_response(&packet), ResponseKind::NoData);
 
-    use std::net::{IpAddr, Ipv4Addr};
+        // SOA along with the zone's NS records
+        let packet = response(
+            true,
+            DnsRCode::NoError,
+            vec![soa_record(), ns_record("example.com", "ns1.example.com")],
+        );
+        assert_eq!(classify_response(&packet), ResponseKind::NoData);
 
-    use crate::dns::protocol;
+        // Authoritative, but with nothing at all in the authority section
+        let packet = response(true, DnsRCode::NoError, vec![]);
+        assert_eq!(classify_response(&packet), ResponseKind::NoData);
+    }
 
     #[test]
-    fn test_ns_query() {
-        let question = protocol::DnsQuestion {
-            qname: vec!["google".to_owned(), "com".to_owned()],
-            qtype: protocol::DnsRRType::A,
-            qclass: protocol::DnsClass::IN,
-        };
-        // TODO not a great practice that this test requires a network connection
-        let ns = IpAddr::V4(Ipv4Addr::new(192, 203, 230, 10));
-        let packet = query_nameserver(&question, ns).expect("query should have worked");
-        println!("{:?}", packet);
+    fn referrals_and_errors_are_recognised() {
+        let packet = response(
+            false,
+            DnsRCode::NoError,
+            vec![ns_record("example.com", "ns1.example.com")],
+        );
+        assert_eq!(classify_response(&packet), ResponseKind::Referral);
+
+        let packet = response(true, DnsRCode::NXDomain, vec![soa_record()]);
+        assert_eq!(classify_response(&packet), ResponseKind::NXDomain);
+
+        let packet = response(false, DnsRCode::ServFail, vec![]);
+        assert_eq!(classify_response(&packet), ResponseKind::Error);
+
+        let packet = response(false, DnsRCode::NoError, vec![]);
+        assert_eq!(classify_response(&packet), ResponseKind::Invalid);
+    }
+
+    #[test]
+    fn non_authoritative_soa_is_not_nodata() {
+        let mut packet = response(false, DnsRCode::NoError, vec![soa_record()]);
+        packet.questions[0].qname = parse_name("nonauthoritative.example.com");
+        assert_eq!(classify_response(&packet), ResponseKind::Invalid);
+
+        // So it's passed over rather than cached as a negative answer
+        let ns = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53));
+        let zone = parse_name("example.com");
+        assert!(usable_response(ns, &zone, Ok(packet)).is_none());
+        let cached = cache::global().lookup(
+            &parse_name("nonauthoritative.example.com"),
+            DnsRRType::AAAA,
+            DnsClass::IN,
+        );
+        assert_eq!(cached, None);
+    }
+
+    #[test]
+    fn lame_servers_are_recognised() {
+        let zone = parse_name("example.com");
+        let packet = response(false, DnsRCode::Refused, vec![]);
+        assert!(lame_reason(&packet, &zone).is_some());
+
+        // Referrals to a parent zone, or back to the zone itself
+        let packet = response(
+            false,
+            DnsRCode::NoError,
+            vec![ns_record("com", "a.gtld-servers.net")],
+        );
+        assert!(lame_reason(&packet, &zone).is_some());
+        let packet = response(
+            false,
+            DnsRCode::NoError,
+            vec![ns_record("example.com", "ns1.example.com")],
+        );
+        assert!(lame_reason(&packet, &zone).is_some());
+
+        // A non-authoritative answer
+        let mut packet = response(false, DnsRCode::NoError, vec![]);
+        packet.answers = vec![a_record("www.example.com", Ipv4Addr::new(192, 0, 2, 1))];
+        assert!(lame_reason(&packet, &zone).is_some());
+
+        // But a referral further down, an authoritative answer, or a server failure are all fine
+        let packet = response(
+            false,
+            DnsRCode::NoError,
+            vec![ns_record("www.example.com", "ns1.example.com")],
+        );
+        assert_eq!(lame_reason(&packet, &zone), None);
+        let mut packet = response(true, DnsRCode::NoError, vec![]);
+        packet.answers = vec![a_record("www.example.com", Ipv4Addr::new(192, 0, 2, 1))];
+        assert_eq!(lame_reason(&packet, &zone), None);
+        let packet = response(false, DnsRCode::ServFail, vec![]);
+        assert_eq!(lame_reason(&packet, &zone), None);
+    }
+
+    fn a_record(name: &str, address: Ipv4Addr) -> DnsResourceRecord {
+        DnsResourceRecord {
+            name: parse_name(name),
+            rr_type: DnsRRType::A,
+            class: DnsClass::IN,
+            ttl: 3600,
+            record: DnsRecordData::A(address),
+        }
+    }
+
+    #[test]
+    fn referral_keeps_every_nameserver_and_address() {
+        let mut packet = response(
+            false,
+            DnsRCode::NoError,
+            vec![
+                ns_record("example.com", "a.iana-servers.net"),
+                ns_record("example.com", "b.iana-servers.net"),
+                ns_record("example.com", "ns.example.org"),
+            ],
+        );
+        packet.addl_recs = vec![
+            a_record("a.iana-servers.net", Ipv4Addr::new(199, 43, 135, 53)),
+            a_record("B.iana-servers.net", Ipv4Addr::new(199, 43, 133, 53)),
+            a_record("a.iana-servers.net", Ipv4Addr::new(192, 0, 2, 53)),
+        ];
+
+        let (delegation, ttl) = delegation_from_referral(&packet);
+        assert_eq!(delegation.zone, parse_name("example.com"));
+        // The lowest TTL of any NS or glue record involved
+        assert_eq!(ttl, 3600);
+        let servers: Vec<(Vec<String>, Vec<IpAddr>)> = delegation
+            .nameservers
+            .into_iter()
+            .map(|ns| (ns.name, ns.addresses))
+            .collect();
+        assert_eq!(
+            servers,
+            vec![
+                (
+                    parse_name("a.iana-servers.net"),
+                    vec![
+                        IpAddr::V4(Ipv4Addr::new(199, 43, 135, 53)),
+                        IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53)),
+                    ]
+                ),
+                (
+                    parse_name("b.iana-servers.net"),
+                    vec![IpAddr::V4(Ipv4Addr::new(199, 43, 133, 53))]
+                ),
+                // No glue, so this one would have to be looked up
+                (parse_name("ns.example.org"), vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn referral_keeps_ipv6_glue() {
+        let mut packet = response(
+            false,
+            DnsRCode::NoError,
+            vec![ns_record("example.com", "a.iana-servers.net")],
+        );
+        let v6 = Ipv6Addr::new(0x2001, 0x500, 0x8f, 0, 0, 0, 0, 0x53);
+        packet.addl_recs = vec![
+            a_record("a.iana-servers.net", Ipv4Addr::new(199, 43, 135, 53)),
+            DnsResourceRecord {
+                name: parse_name("a.iana-servers.net"),
+                rr_type: DnsRRType::AAAA,
+                class: DnsClass::IN,
+                ttl: 3600,
+                record: DnsRecordData::AAAA(v6),
+            },
+        ];
+
+        let (delegation, _) = delegation_from_referral(&packet);
+        assert_eq!(
+            delegation.nameservers[0].addresses,
+            vec![IpAddr::V4(Ipv4Addr::new(199, 43, 135, 53)), IpAddr::V6(v6)]
+        );
+    }
+
+    #[test]
+    fn only_accepted_chain_records_are_cached() {
+        // The authority's CNAME for www.dname-cache.test doesn't match the DNAME it came with,
+        // so it shouldn't end up in the cache, and neither should the records it points to
+        let question = question("www.dname-cache.test", DnsRRType::A);
+        let mut packet = response(true, DnsRCode::NoError, vec![]);
+        packet.questions = vec![question.to_owned()];
+        packet.answers = vec![
+            DnsResourceRecord {
+                name: parse_name("dname-cache.test"),
+                rr_type: DnsRRType::DNAME,
+                class: DnsClass::IN,
+                ttl: 3600,
+                record: DnsRecordData::DNAME(parse_name("dname-target.test")),
+            },
+            DnsResourceRecord {
+                name: parse_name("www.dname-cache.test"),
+                rr_type: DnsRRType::CNAME,
+                class: DnsClass::IN,
+                ttl: 3600,
+                record: DnsRecordData::CNAME(parse_name("www.elsewhere.test")),
+            },
+            a_record("www.elsewhere.test", Ipv4Addr::new(192, 0, 2, 66)),
+            a_record("www.dname-target.test", Ipv4Addr::new(192, 0, 2, 1)),
+        ];
+
+        cache_response(&question, &packet);
+        let cache = cache::global();
+        match cache.lookup(&question.qname, DnsRRType::CNAME, DnsClass::IN) {
+            Some(CachedAnswer::Records(records)) => assert_eq!(
+                records[0].record,
+                DnsRecordData::CNAME(parse_name("www.dname-target.test"))
+            ),
+            other => panic!("expected the synthesized CNAME, got {:?}", other),
+        }
+        assert!(cache
+            .lookup(
+                &parse_name("www.dname-target.test"),
+                DnsRRType::A,
+                DnsClass::IN
+            )
+            .is_some());
+        assert!(cache
+            .lookup(
+                &parse_name("www.elsewhere.test"),
+                DnsRRType::A,
+                DnsClass::IN
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn nxdomain_after_a_cname_is_cached_for_the_target() {
+        let question = question("alias.nx-chain.test", DnsRRType::A);
+        let mut packet = response(true, DnsRCode::NXDomain, vec![soa_record()]);
+        packet.questions = vec![question.to_owned()];
+        packet.answers = vec![DnsResourceRecord {
+            name: parse_name("alias.nx-chain.test"),
+            rr_type: DnsRRType::CNAME,
+            class: DnsClass::IN,
+            ttl: 3600,
+            record: DnsRecordData::CNAME(parse_name("gone.nx-chain.test")),
+        }];
+
+        cache_response(&question, &packet);
+        let cache = cache::global();
+        // The alias exists; it's the name it points to that doesn't
+        assert!(matches!(
+            cache.lookup(&question.qname, DnsRRType::CNAME, DnsClass::IN),
+            Some(CachedAnswer::Records(_))
+        ));
+        assert!(!matches!(
+            cache.lookup(&question.qname, DnsRRType::A, DnsClass::IN),
+            Some(CachedAnswer::NXDomain(_))
+        ));
+        assert!(matches!(
+            cache.lookup(
+                &parse_name("gone.nx-chain.test"),
+                DnsRRType::A,
+                DnsClass::IN
+            ),
+            Some(CachedAnswer::NXDomain(_))
+        ));
     }
 }
@@ -0,0 +1,202 @@
+// Async resolution on tokio
+//
+// The blocking resolver ties up a thread for every query it's waiting on, and the server a thread
+// for every client question. This is the same resolver with async I/O, so one runtime can have tens
+// of thousands of resolutions on the go, each just a task waiting on a socket. Only the I/O lives
+// here: where to start, what to make of each response, which servers to ask in which order, and
+// what to cache are all decided by the helpers the blocking resolver uses.
+//
+// The A and AAAA lookups for a nameserver we have no glue for run at the same time, rather than
+// one after the other.
+//
+// The background jobs (priming, health checks, stats) still run on threads of their own.
+
+pub mod query;
+
+pub use query::{query_authority, query_nameserver, timed_query};
+
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+
+use futures_util::future::join_all;
+
+use super::context::ResolutionContext;
+use super::forward::{self, UpstreamPool};
+use super::minimise::Minimiser;
+use super::zones;
+use super::{
+    addresses_from_lookups, chain_from_answers, config, finish_answers, follow_response,
+    is_limit_error, learned_addresses, minimised_outcome, nameserver_questions, no_usable_response,
+    plan_servers, starting_point, usable_response, Delegation, ResponseKind, Start, Step,
+};
+use crate::dns::protocol::{DnsPacket, DnsQuestion};
+
+pub use super::Result;
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub async fn resolve_question(question: &DnsQuestion) -> Result<DnsPacket> {
+    let mut context = ResolutionContext::new();
+    resolve_in_context(question, &mut context).await
+}
+
+// As in the blocking resolver, everything that resolves another question goes through here. It's
+// boxed since resolution is recursive (CNAMEs and nameserver lookups), and an async fn can't call
+// itself directly.
+fn resolve_in_context<'a>(
+    question: &'a DnsQuestion,
+    context: &'a mut ResolutionContext,
+) -> BoxFuture<'a, Result<DnsPacket>> {
+    Box::pin(async move {
+        context.enter(question)?;
+        let result = resolve_iteratively(question, context).await;
+        context.exit();
+        result
+    })
+}
+
+async fn resolve_iteratively(
+    question: &DnsQuestion,
+    context: &mut ResolutionContext,
+) -> Result<DnsPacket> {
+    let mut zone = match starting_point(question) {
+        Start::Cached(response) => return handle_answers(response, context).await,
+        Start::Forward(zone, pool) => {
+            context.count_query()?;
+            let response = forward_to(pool, Some(zone), question).await?;
+            if response.answers.is_empty() {
+                return Ok(response);
+            }
+            return handle_answers(response, context).await;
+        }
+        Start::Iterate(zone) => zone,
+    };
+    let mut minimiser = Minimiser::new(config::get().qname_minimisation, &zone.zone);
+    loop {
+        let (response, kind) = match minimiser.next_question(question) {
+            Some(minimised) => {
+                let result = query_delegation(&minimised, &zone, context).await;
+                match minimised_outcome(&minimised, question, result, &mut minimiser)? {
+                    Some(result) => result,
+                    None => continue,
+                }
+            }
+            None => query_delegation(question, &zone, context).await?,
+        };
+        match follow_response(question, response, kind, &mut minimiser) {
+            Step::Answer(response) => return handle_answers(response, context).await,
+            Step::Done(response) => return Ok(response),
+            Step::Referral(next_zone) => zone = next_zone,
+        }
+    }
+}
+
+// Ask the servers for `zone` our question until one of them gives us a usable response. Nameservers
+// without glue are looked up one at a time, the next only once the last one's addresses didn't get
+// us anywhere, so a zone with lots of them doesn't set off lookups for all of them at once.
+async fn query_delegation(
+    question: &DnsQuestion,
+    zone: &Delegation,
+    context: &mut ResolutionContext,
+) -> Result<(DnsPacket, ResponseKind)> {
+    let servers = plan_servers(zone);
+    for address in servers.addresses {
+        if let Some(result) = try_nameserver(question, address, &zone.zone, context).await? {
+            return Ok(result);
+        }
+    }
+    for ns_name in &servers.unaddressed {
+        let addresses = match get_nameserver_address(ns_name, context).await {
+            Ok(addresses) => addresses,
+            Err(error) if is_limit_error(error.as_ref()) => return Err(error),
+            Err(error) => {
+                log!(
+                    "Couldn't find address for nameserver {:?}: {}",
+                    ns_name,
+                    error
+                );
+                continue;
+            }
+        };
+        for address in learned_addresses(zone, ns_name, addresses) {
+            if let Some(result) = try_nameserver(question, address, &zone.zone, context).await? {
+                return Ok(result);
+            }
+        }
+    }
+    Err(no_usable_response(zone))
+}
+
+async fn try_nameserver(
+    question: &DnsQuestion,
+    ns: IpAddr,
+    zone: &[String],
+    context: &mut ResolutionContext,
+) -> Result<Option<(DnsPacket, ResponseKind)>> {
+    log!("Asking authority at {:?} question: {:?}", ns, question);
+    context.count_query()?;
+    let result = query_authority(question, ns, &config::get().query).await;
+    Ok(usable_response(ns, zone, result))
+}
+
+async fn handle_answers(response: DnsPacket, context: &mut ResolutionContext) -> Result<DnsPacket> {
+    let (chain, next) = chain_from_answers(&response)?;
+    let rest = match next {
+        Some(next) => {
+            let reply = resolve_in_context(&next, context).await?;
+            Some((next, reply))
+        }
+        None => None,
+    };
+    finish_answers(response, chain, rest)
+}
+
+// The A and AAAA lookups for a nameserver run at the same time, each with a fork of the context
+async fn get_nameserver_address(
+    ns_name: &[String],
+    context: &mut ResolutionContext,
+) -> Result<Vec<IpAddr>> {
+    context.enter_nameserver(ns_name)?;
+    let lookups = nameserver_questions(ns_name).into_iter().map(|question| {
+        let mut fork = context.fork();
+        async move {
+            let result = resolve_in_context(&question, &mut fork).await;
+            (question.qtype, result)
+        }
+    });
+    let results = join_all(lookups).await;
+    context.exit_nameserver();
+    addresses_from_lookups(results)
+}
+
+// Answer a question from the cache if we can, and by asking upstream if we can't
+pub async fn forward_question(question: &DnsQuestion) -> Result<DnsPacket> {
+    let pool = forward::pool().ok_or("No upstream resolvers are configured")?;
+    if zones::global().find(&question.qname).is_some() {
+        return resolve_question(question).await;
+    }
+    if let Some(response) = forward::cached_answer(question) {
+        log!("Answering from cache: {:?}", response);
+        return Ok(response);
+    }
+    forward_to(pool, None, question).await
+}
+
+// Ask the upstreams in `pool` about `question` in turn until one of them answers, the same way as
+// UpstreamPool::forward. `zone` is the forward zone the pool is for, if it's one of those.
+async fn forward_to(
+    pool: &UpstreamPool,
+    zone: Option<&[String]>,
+    question: &DnsQuestion,
+) -> Result<DnsPacket> {
+    let options = forward::upstream_options();
+    for upstream in pool.order() {
+        log!("Forwarding {:?} to {}", question, upstream);
+        let result = timed_query(question, upstream, &options).await;
+        if let Some(reply) = pool.record_reply(upstream, result) {
+            return Ok(forward::forwarded(question, zone, reply));
+        }
+    }
+    Err(forward::no_upstream_answered(question))
+}
@@ -0,0 +1,255 @@
+// Sending queries without blocking
+//
+// These are the same queries as the ones in recursive::query, sent and checked by the same code:
+// random IDs, ports and name case, EDNS, the fallback lists, and retrying truncated replies over
+// TCP. The difference is that waiting for a reply parks a task on the tokio runtime rather than a
+// whole thread, so any number of them can be outstanding at once.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time;
+
+use super::super::errors::QueryError;
+use super::super::query::{
+    authority_options, build_query, case_mismatch, check_reply, check_tcp_reply, global,
+    mangles_case, randomize_case, record_result, rejects_edns, restore_case, tcp_message,
+    unspecified_address, QueryOptions, TimedReply, CASE_ATTEMPTS,
+};
+use crate::dns::protocol::{DnsPacket, DnsQuestion, DnsRRType};
+
+// Sends a query to port 53 on an authoritative nameserver, timing it for the infra cache
+pub async fn query_authority(
+    question: &DnsQuestion,
+    ns: IpAddr,
+    options: &QueryOptions,
+) -> Result<DnsPacket, QueryError> {
+    let result = timed_query(
+        question,
+        SocketAddr::new(ns, 53),
+        &authority_options(ns, options),
+    )
+    .await;
+    record_result(ns, &result);
+    result.map(|timed| timed.reply)
+}
+
+// Sends a query to a nameserver, whether that's an authority or an upstream resolver
+pub async fn query_nameserver(
+    question: &DnsQuestion,
+    ns: SocketAddr,
+    options: &QueryOptions,
+) -> Result<DnsPacket, QueryError> {
+    timed_query(question, ns, options)
+        .await
+        .map(|timed| timed.reply)
+}
+
+// The same, along with an RTT sample for the server if the reply gave us one
+pub async fn timed_query(
+    question: &DnsQuestion,
+    ns: SocketAddr,
+    options: &QueryOptions,
+) -> Result<TimedReply, QueryError> {
+    let edns = options.edns && !global().edns_fallback().contains(ns);
+    let mut timed = send_query(question, edns, ns, options).await?;
+    if edns && rejects_edns(&timed.reply, ns, global()) {
+        timed = send_query(question, false, ns, options).await?;
+    }
+
+    // The OPT record only describes this hop between us and the server
+    timed
+        .reply
+        .addl_recs
+        .retain(|rr| rr.rr_type != DnsRRType::OPT);
+    Ok(timed)
+}
+
+// Send our question to the server, randomising its case unless the server is known not to cope
+async fn send_query(
+    question: &DnsQuestion,
+    edns: bool,
+    ns: SocketAddr,
+    options: &QueryOptions,
+) -> Result<TimedReply, QueryError> {
+    if options.randomize_case && !global().case_fallback().contains(ns) {
+        for _ in 0..CASE_ATTEMPTS {
+            let mixed = DnsQuestion {
+                qname: randomize_case(&question.qname),
+                ..question.to_owned()
+            };
+            match exchange(&mixed, true, edns, ns, options).await {
+                Ok(mut timed) => {
+                    restore_case(&mut timed.reply, question);
+                    return Ok(timed);
+                }
+                Err(QueryError::CaseMismatch(_)) => case_mismatch(ns, global()),
+                Err(error) => return Err(error),
+            }
+        }
+        mangles_case(ns, global());
+    }
+    let mut timed = exchange(question, false, edns, ns, options).await?;
+    restore_case(&mut timed.reply, question);
+    Ok(timed)
+}
+
+// Send a single question to a server and wait for the reply, retransmitting as needed
+async fn exchange(
+    question: &DnsQuestion,
+    exact_case: bool,
+    edns: bool,
+    ns: SocketAddr,
+    options: &QueryOptions,
+) -> Result<TimedReply, QueryError> {
+    let packet = build_query(question, edns, options);
+    let query_bytes = packet.to_bytes();
+
+    // A fresh socket (and so a fresh random port) for every query, as in the blocking version
+    let socket = UdpSocket::bind(unspecified_address(&ns)).await?;
+    let mut buf = vec![0; u16::MAX.into()];
+    let mut case_mismatched = false;
+
+    for (attempt, timeout) in options.schedule().into_iter().enumerate() {
+        socket.send_to(&query_bytes, ns).await?;
+        let sent = Instant::now();
+        let deadline = time::Instant::now() + timeout;
+        loop {
+            let (amt, source) = match time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+                Ok(received) => received?,
+                Err(_) if case_mismatched => return Err(QueryError::CaseMismatch(ns)),
+                Err(_) => {
+                    log!("No reply from {} after {:?}", ns, timeout);
+                    break;
+                }
+            };
+
+            match check_reply(&packet, exact_case, ns, source, &buf[..amt], global()) {
+                Ok(None) => continue,
+                Err(QueryError::CaseMismatch(_)) => case_mismatched = true,
+                Err(error) => return Err(error),
+                Ok(Some(reply)) if reply.flags.tc_bit => {
+                    log!("Reply from {} was truncated, retrying over TCP", ns);
+                    let reply = exchange_tcp(&packet, ns, options).await?;
+                    return Ok(TimedReply { reply, rtt: None });
+                }
+                Ok(Some(reply)) => {
+                    let rtt = (attempt == 0).then(|| sent.elapsed());
+                    return Ok(TimedReply { reply, rtt });
+                }
+            }
+        }
+    }
+
+    Err(QueryError::Timeout(ns))
+}
+
+// Send a query over TCP and read back the reply, giving the server `max_timeout` to connect and
+// the same again to answer
+async fn exchange_tcp(
+    query: &DnsPacket,
+    ns: SocketAddr,
+    options: &QueryOptions,
+) -> Result<DnsPacket, QueryError> {
+    let mut stream = time::timeout(options.max_timeout, TcpStream::connect(ns))
+        .await
+        .map_err(|_| QueryError::Timeout(ns))??;
+
+    let message = time::timeout(options.max_timeout, async {
+        stream.write_all(&tcp_message(query)).await?;
+        let mut length = [0; 2];
+        stream.read_exact(&mut length).await?;
+        let mut buf = vec![0; u16::from_be_bytes(length).into()];
+        stream.read_exact(&mut buf).await?;
+        Ok::<_, QueryError>(buf)
+    })
+    .await
+    .map_err(|_| QueryError::Timeout(ns))??;
+    check_tcp_reply(query, ns, &message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::protocol::*;
+    use crate::dns::recursive::testing::{question, reply_to};
+
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn forged_replies_are_ignored() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ns = server.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (amt, client) = server.recv_from(&mut buf).await.unwrap();
+            let query = DnsPacket::from_bytes(&buf[..amt]).unwrap();
+
+            // A guess at the ID, then the real thing
+            let mut forged = reply_to(&query);
+            forged.id = query.id.wrapping_add(1);
+            forged.flags.rcode = DnsRCode::NXDomain;
+            server.send_to(&forged.to_bytes(), client).await.unwrap();
+            server
+                .send_to(&reply_to(&query).to_bytes(), client)
+                .await
+                .unwrap();
+        });
+
+        let options = QueryOptions {
+            timeout: Duration::from_secs(2),
+            attempts: 1,
+            ..QueryOptions::default()
+        };
+        let reply = query_nameserver(&question("www.example.com", DnsRRType::A), ns, &options)
+            .await
+            .expect("should get the real reply");
+        assert_eq!(reply.flags.rcode, DnsRCode::NoError);
+        assert_eq!(
+            reply.questions[0].qname,
+            question("www.example.com", DnsRRType::A).qname
+        );
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn queries_are_outstanding_at_the_same_time() {
+        // A server that won't answer anything until it's been asked two questions, which only
+        // works out if neither query waits for the other
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ns = server.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let mut queries = Vec::new();
+            for _ in 0..2 {
+                let (amt, client) = server.recv_from(&mut buf).await.unwrap();
+                queries.push((DnsPacket::from_bytes(&buf[..amt]).unwrap(), client));
+            }
+            for (query, client) in queries {
+                server
+                    .send_to(&reply_to(&query).to_bytes(), client)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let options = QueryOptions {
+            timeout: Duration::from_secs(2),
+            attempts: 1,
+            ..QueryOptions::default()
+        };
+        let (a, b) = (
+            question("a.example.com", DnsRRType::A),
+            question("b.example.com", DnsRRType::A),
+        );
+        let (first, second) = tokio::join!(
+            query_nameserver(&a, ns, &options),
+            query_nameserver(&b, ns, &options),
+        );
+        assert!(first.is_ok());
+        assert!(second.is_ok());
+        handle.await.unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+// Root priming (RFC 8109)
+//
+// Root hints go stale: root servers get renumbered every few years, and a hints file can sit
+// untouched on disk for much longer than that. So rather than trusting them for every lookup, we
+// use them once to ask a root for the current list of root servers (a ". NS" query, the "priming
+// query") and use the answer until its TTL runs out, then ask again. If priming fails, lookups
+// carry on using the hints.
+
+use std::net::IpAddr;
+use std::thread;
+use std::time::Duration;
+
+use super::bailiwick;
+use super::config;
+use super::delegation::Nameserver;
+use super::metrics::{self, Metrics};
+use super::query;
+use super::root;
+use crate::dns::protocol::{
+    lowercase_name, DnsClass, DnsPacket, DnsQuestion, DnsRCode, DnsRRType, DnsRecordData,
+};
+
+// However short the root NS TTL is, don't prime more often than this
+const MIN_REFRESH: Duration = Duration::from_secs(60);
+
+// How long to wait before trying again after priming fails
+const RETRY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+// Send a priming query, trying root servers until one gives us a usable answer. On success, the
+// root servers from the answer replace the hints and we return how long they're good for.
+pub fn prime() -> Result<u32, String> {
+    Metrics::increment(&metrics::global().priming_attempts);
+    let question = DnsQuestion {
+        qname: vec![],
+        qtype: DnsRRType::NS,
+        qclass: DnsClass::IN,
+    };
+    let hints = root::hints();
+
+    let delegation = hints.delegation(config::get().root_selection);
+    let preference = config::get().ip_preference;
+    let addresses = delegation.addresses();
+    for address in addresses
+        .into_iter()
+        .filter(|address| preference.allows(address))
+    {
+        log!("Sending priming query to {:?}", address);
+        let mut response = match query::query_authority(&question, address, &config::get().query) {
+            Ok(response) => response,
+            Err(error) => {
+                log!("Priming query to {:?} failed: {}", address, error);
+                continue;
+            }
+        };
+
+        bailiwick::scrub_response(&mut response, &[]);
+        match roots_from_response(&response, hints.hinted()) {
+            Ok((nameservers, ttl)) => {
+                log!(
+                    "Primed {} root servers from {:?}, refreshing in {}s",
+                    nameservers.len(),
+                    address,
+                    ttl
+                );
+                hints.set_primed(nameservers, ttl);
+                Metrics::increment(&metrics::global().priming_successes);
+                return Ok(ttl);
+            }
+            Err(error) => log!("Unusable priming response from {:?}: {}", address, error),
+        }
+    }
+
+    Metrics::increment(&metrics::global().priming_failures);
+    Err("No root server gave a usable priming response".to_owned())
+}
+
+// Prime now, and again whenever the root servers we learned are about to expire. This runs on its
+// own thread for as long as the resolver does.
+pub fn start() {
+    thread::spawn(|| loop {
+        let wait = match prime() {
+            // Refresh a little before the old list expires, so lookups don't drop back to the
+            // hints in between
+            Ok(ttl) => (Duration::from_secs(ttl.into()) * 9 / 10).max(MIN_REFRESH),
+            Err(error) => {
+                log!("Root priming failed, using root hints: {}", error);
+                RETRY_AFTER
+            }
+        };
+        log!("Priming metrics: {:?}", metrics::global().snapshot());
+        thread::sleep(wait);
+    });
+}
+
+// Pull the root servers out of a priming response: the NS records for the root in the answer
+// section, with addresses from the additional section. RFC 8109 says the response might not have
+// addresses for every server, in which case we use the ones from the hints if the hints know the
+// server. Also returns the lowest TTL of the records we used.
+fn roots_from_response(
+    response: &DnsPacket,
+    hints: &[Nameserver],
+) -> Result<(Vec<Nameserver>, u32), String> {
+    if response.flags.rcode != DnsRCode::NoError {
+        return Err(format!("Got {:?}", response.flags.rcode));
+    }
+    if !response.flags.aa_bit {
+        return Err("Response wasn't authoritative".to_owned());
+    }
+
+    let mut ttl = u32::MAX;
+    let mut nameservers = Vec::new();
+    for rr in &response.answers {
+        let ns_name = match &rr.record {
+            DnsRecordData::NS(name) if rr.name.is_empty() => name,
+            _ => continue,
+        };
+        let ns_key = lowercase_name(ns_name);
+        ttl = ttl.min(rr.ttl);
+
+        let mut addresses = Vec::new();
+        for glue in &response.addl_recs {
+            if lowercase_name(&glue.name) != ns_key {
+                continue;
+            }
+            match glue.record {
+                DnsRecordData::A(address) => addresses.push(IpAddr::V4(address)),
+                DnsRecordData::AAAA(address) => addresses.push(IpAddr::V6(address)),
+                _ => continue,
+            }
+            ttl = ttl.min(glue.ttl);
+        }
+        if addresses.is_empty() {
+            if let Some(hinted) = hints
+                .iter()
+                .find(|hinted| lowercase_name(&hinted.name) == ns_key)
+            {
+                addresses = hinted.addresses.to_owned();
+            }
+        }
+        if !addresses.is_empty() {
+            nameservers.push(Nameserver {
+                name: ns_name.to_owned(),
+                addresses,
+            });
+        }
+    }
+
+    if nameservers.is_empty() {
+        return Err("No root servers with addresses in the response".to_owned());
+    }
+    Ok((nameservers, ttl))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::protocol::*;
+
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn record(name: &str, ttl: u32, record: DnsRecordData) -> DnsResourceRecord {
+        let rr_type = match record {
+            DnsRecordData::NS(_) => DnsRRType::NS,
+            DnsRecordData::A(_) => DnsRRType::A,
+            _ => DnsRRType::AAAA,
+        };
+        DnsResourceRecord {
+            name: parse_name(name),
+            rr_type,
+            class: DnsClass::IN,
+            ttl,
+            record,
+        }
+    }
+
+    fn priming_response(aa_bit: bool) -> DnsPacket {
+        let mut flags = DnsFlags::from_bytes(&[0x80, 0x00]).unwrap();
+        flags.aa_bit = aa_bit;
+        DnsPacket {
+            id: 1,
+            flags,
+            questions: vec![DnsQuestion {
+                qname: vec![],
+                qtype: DnsRRType::NS,
+                qclass: DnsClass::IN,
+            }],
+            answers: vec![
+                record(
+                    "",
+                    518400,
+                    DnsRecordData::NS(parse_name("a.root-servers.net")),
+                ),
+                record(
+                    "",
+                    518400,
+                    DnsRecordData::NS(parse_name("b.root-servers.net")),
+                ),
+                record(
+                    "",
+                    518400,
+                    DnsRecordData::NS(parse_name("x.root-servers.net")),
+                ),
+            ],
+            nameservers: vec![],
+            addl_recs: vec![
+                record(
+                    "A.ROOT-SERVERS.NET",
+                    3600000,
+                    DnsRecordData::A(Ipv4Addr::new(198, 41, 0, 4)),
+                ),
+                record(
+                    "a.root-servers.net",
+                    3600000,
+                    DnsRecordData::AAAA(Ipv6Addr::new(0x2001, 0x503, 0xba3e, 0, 0, 0, 0x2, 0x30)),
+                ),
+            ],
+        }
+    }
+
+    #[test]
+    fn roots_come_from_the_priming_answer() {
+        let hints = vec![Nameserver {
+            name: parse_name("b.root-servers.net"),
+            addresses: vec![IpAddr::V4(Ipv4Addr::new(170, 247, 170, 2))],
+        }];
+        let (roots, ttl) =
+            roots_from_response(&priming_response(true), &hints).expect("should be usable");
+        assert_eq!(ttl, 518400);
+        assert_eq!(
+            roots,
+            vec![
+                Nameserver {
+                    name: parse_name("a.root-servers.net"),
+                    addresses: vec![
+                        IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4)),
+                        IpAddr::V6(Ipv6Addr::new(0x2001, 0x503, 0xba3e, 0, 0, 0, 0x2, 0x30)),
+                    ],
+                },
+                // No glue in the response, so the address comes from the hints
+                hints[0].to_owned(),
+                // x.root-servers.net has no address anywhere, so it's left out
+            ]
+        );
+    }
+
+    #[test]
+    fn unusable_priming_responses_are_rejected() {
+        assert!(roots_from_response(&priming_response(false), &[]).is_err());
+
+        let mut servfail = priming_response(true);
+        servfail.flags.rcode = DnsRCode::ServFail;
+        assert!(roots_from_response(&servfail, &[]).is_err());
+
+        let mut empty = priming_response(true);
+        empty.addl_recs.clear();
+        assert!(roots_from_response(&empty, &[]).is_err());
+    }
+}
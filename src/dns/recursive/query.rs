@@ -0,0 +1,1072 @@
+// Sending queries to authoritative nameservers
+//
+// UDP gives us no guarantee a query (or its reply) ever arrives, so we wait a limited time for the
+// reply and retransmit if it doesn't show up, waiting a little longer each time. If a server
+// still hasn't answered after the last attempt, we report a timeout so the resolver can move on
+// to a different server.
+//
+// If a reply is too big for a datagram, the server sends back as much as fits with the TC bit set,
+// and we ask again over TCP to get the whole thing.
+//
+// We also have to assume someone off-path is trying to slip us forged replies (the Kaminsky cache
+// poisoning attack). To make that hard, every query gets a random transaction ID and goes out
+// from a fresh socket on a port the OS picks at random, and we only accept a reply that comes
+// from the address we sent to, with the right ID and our question echoed back. Anything else is
+// dropped on the floor while we keep waiting for the real reply.
+//
+// On top of that, we randomise the case of the letters in the query name ("dns 0x20", see
+// draft-vixie-dnsext-dns0x20). Servers copy the question into their reply byte for byte, so a
+// reply has to match our exact mix of upper and lower case too. A reply that gets the case wrong is
+// ignored while we wait for the right one, as it might be forged. A few servers don't preserve
+// case, though; when one of those keeps giving us otherwise valid replies and nothing better, it
+// goes on a fallback list and gets plain queries for a while.
+//
+// Queries also carry an EDNS0 OPT record (RFC 6891) advertising how big a UDP reply we can take,
+// so servers aren't stuck with the original 512 byte limit. The default of 1232 bytes is the one
+// agreed on for DNS Flag Day 2020: it fits in a single packet over IPv6 without fragmenting, and
+// anything bigger comes over TCP instead. Servers old enough not to understand EDNS reply with
+// FORMERR or NOTIMP; they also go on a fallback list, and get queries without the OPT record.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use super::errors::QueryError;
+use super::infra;
+use crate::dns::protocol::{
+    lowercase_name, DnsClass, DnsFlags, DnsOpcode, DnsPacket, DnsQuestion, DnsRCode, DnsRRType,
+    DnsRecordData, DnsResourceRecord,
+};
+
+#[derive(Clone, Debug)]
+pub struct QueryOptions {
+    // How long to wait for a reply to the first transmission
+    pub timeout: Duration,
+    // How many times to send the query before giving up on the server
+    pub attempts: u32,
+    // Each retransmission waits twice as long as the one before it, up to this long
+    pub max_timeout: Duration,
+    // Whether to randomise the case of query names (DNS 0x20)
+    pub randomize_case: bool,
+    // Whether to send an EDNS0 OPT record, and the UDP payload size to advertise in it
+    pub edns: bool,
+    pub edns_payload_size: u16,
+    // Whether to ask for DNSSEC records, by setting the DO bit in the OPT record
+    pub dnssec_ok: bool,
+    // Whether to set RD, asking the server to recurse for us. Authorities don't, but upstream
+    // resolvers do.
+    pub recursion_desired: bool,
+}
+
+impl Default for QueryOptions {
+    fn default() -> QueryOptions {
+        QueryOptions {
+            timeout: Duration::from_millis(800),
+            attempts: 3,
+            max_timeout: Duration::from_secs(3),
+            randomize_case: true,
+            edns: true,
+            edns_payload_size: 1232,
+            dnssec_ok: false,
+            recursion_desired: false,
+        }
+    }
+}
+
+impl QueryOptions {
+    // How long to wait after each transmission, in order
+    pub fn schedule(&self) -> Vec<Duration> {
+        let mut timeout = self.timeout;
+        let mut schedule = Vec::new();
+        for _ in 0..self.attempts {
+            schedule.push(timeout.min(self.max_timeout));
+            timeout *= 2;
+        }
+        schedule
+    }
+}
+
+// A reply, along with the round trip time it gives us for the server. Following Karn's algorithm
+// (RFC 6298 section 3), we only have a sample when the reply came back for a query we sent just
+// once over UDP. After a retransmission there's no telling which copy the reply is for, and a
+// reply over TCP includes setting up the connection.
+pub struct TimedReply {
+    pub reply: DnsPacket,
+    pub rtt: Option<Duration>,
+}
+
+// Sends a query to port 53 on an authoritative nameserver. Rather than always waiting the
+// configured time before retransmitting, we wait about as long as the server usually takes to
+// answer (see the infra module), and then record how long it took this time.
+pub fn query_authority(
+    question: &DnsQuestion,
+    ns: IpAddr,
+    options: &QueryOptions,
+) -> Result<DnsPacket, QueryError> {
+    let result = timed_query(
+        question,
+        SocketAddr::new(ns, 53),
+        &authority_options(ns, options),
+        global(),
+    );
+    record_result(ns, &result);
+    result.map(|timed| timed.reply)
+}
+
+// The options for a query to `ns`, with the first timeout set from its RTO if we know it
+pub fn authority_options(ns: IpAddr, options: &QueryOptions) -> QueryOptions {
+    let timeout = infra::global().measured_rto(ns).unwrap_or(options.timeout);
+    QueryOptions {
+        timeout: timeout.min(options.max_timeout),
+        ..options.to_owned()
+    }
+}
+
+// Tell the infra cache how a query to `ns` went
+pub fn record_result(ns: IpAddr, result: &Result<TimedReply, QueryError>) {
+    match result {
+        Ok(TimedReply { rtt: Some(rtt), .. }) => infra::global().record_rtt(ns, *rtt),
+        Err(QueryError::Timeout(_)) => infra::global().record_timeout(ns),
+        _ => (),
+    }
+}
+
+// Sends a query to a nameserver, whether that's an authority or an upstream resolver
+pub fn query_nameserver(
+    question: &DnsQuestion,
+    ns: SocketAddr,
+    options: &QueryOptions,
+) -> Result<DnsPacket, QueryError> {
+    query_with(question, ns, options, global())
+}
+
+// The same, for something other than the resolver, keeping track of servers in its own `state`
+pub fn query_with(
+    question: &DnsQuestion,
+    ns: SocketAddr,
+    options: &QueryOptions,
+    state: &QueryState,
+) -> Result<DnsPacket, QueryError> {
+    timed_query(question, ns, options, state).map(|timed| timed.reply)
+}
+
+// Send a query, and along with the reply, an RTT sample for the server if the reply gave us one
+pub fn timed_query(
+    question: &DnsQuestion,
+    ns: SocketAddr,
+    options: &QueryOptions,
+    state: &QueryState,
+) -> Result<TimedReply, QueryError> {
+    let edns = options.edns && !state.edns_fallback.contains(ns);
+    let mut timed = send_query(question, edns, ns, options, state)?;
+    if edns && rejects_edns(&timed.reply, ns, state) {
+        timed = send_query(question, false, ns, options, state)?;
+    }
+
+    // The OPT record only describes this hop between us and the server, so it doesn't go any
+    // further than here
+    timed
+        .reply
+        .addl_recs
+        .retain(|rr| rr.rr_type != DnsRRType::OPT);
+    Ok(timed)
+}
+
+// Is this reply to an EDNS query from a server that doesn't understand EDNS? If so, it goes on the
+// fallback list.
+pub fn rejects_edns(reply: &DnsPacket, ns: SocketAddr, state: &QueryState) -> bool {
+    if reply.flags.rcode != DnsRCode::FormError && reply.flags.rcode != DnsRCode::NotImp {
+        return false;
+    }
+    log!(in state;
+        "{} returned {:?} to an EDNS query, falling back to plain DNS",
+        ns, reply.flags.rcode
+    );
+    state.edns_fallback.insert(ns);
+    true
+}
+
+// Send our question to the server, randomising its case unless the server is known not to cope
+fn send_query(
+    question: &DnsQuestion,
+    edns: bool,
+    ns: SocketAddr,
+    options: &QueryOptions,
+    state: &QueryState,
+) -> Result<TimedReply, QueryError> {
+    if options.randomize_case && !state.case_fallback.contains(ns) {
+        for _ in 0..CASE_ATTEMPTS {
+            let mixed = DnsQuestion {
+                qname: randomize_case(&question.qname),
+                ..question.to_owned()
+            };
+            match exchange(&mixed, true, edns, ns, options, state) {
+                Ok(mut timed) => {
+                    restore_case(&mut timed.reply, question);
+                    return Ok(timed);
+                }
+                Err(QueryError::CaseMismatch(_)) => case_mismatch(ns, state),
+                Err(error) => return Err(error),
+            }
+        }
+        mangles_case(ns, state);
+    }
+    let mut timed = exchange(question, false, edns, ns, options, state)?;
+    restore_case(&mut timed.reply, question);
+    Ok(timed)
+}
+
+// A reply was right in every way but the case of the name, and no reply with the right case turned
+// up. That's either a server that doesn't echo the question back byte for byte, or a forger who got
+// everything but the case right, so we ask again with a new random case before deciding which.
+pub fn case_mismatch(ns: SocketAddr, state: &QueryState) {
+    log!(in state;
+        "Reply from {} didn't match our query's case, asking again",
+        ns
+    );
+}
+
+// Every reply in CASE_ATTEMPTS tries got the case wrong, so this is a server that doesn't echo the
+// question back byte for byte. Remember that, so we ask it the plain way.
+pub fn mangles_case(ns: SocketAddr, state: &QueryState) {
+    log!(in state;
+        "{} doesn't preserve query name case, falling back to plain queries",
+        ns
+    );
+    state.case_fallback.insert(ns);
+}
+
+// Send a single question to a server and wait for the reply, retransmitting as needed. With
+// `exact_case`, a reply whose question differs from ours only in case is ignored, and if that's all
+// we get by the time we'd retransmit, it's reported as a CaseMismatch. With `edns`, the query
+// carries an OPT record.
+fn exchange(
+    question: &DnsQuestion,
+    exact_case: bool,
+    edns: bool,
+    ns: SocketAddr,
+    options: &QueryOptions,
+    state: &QueryState,
+) -> Result<TimedReply, QueryError> {
+    let packet = build_query(question, edns, options);
+    let query_bytes = packet.to_bytes();
+
+    // Binding to port 0 has the OS pick an ephemeral source port for us, which modern kernels
+    // randomise. A new socket for every query means a new port for every query, too.
+    let socket = UdpSocket::bind(unspecified_address(&ns))?;
+    // Big enough for any datagram, in case a server sends more than we said we could take
+    let mut buf = vec![0; u16::MAX.into()];
+    let mut case_mismatched = false;
+
+    // Send the query, retransmitting on the schedule until a valid reply comes back
+    for (attempt, timeout) in options.schedule().into_iter().enumerate() {
+        socket.send_to(&query_bytes, ns)?;
+        let sent = Instant::now();
+        let deadline = sent + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                if case_mismatched {
+                    return Err(QueryError::CaseMismatch(ns));
+                }
+                log!(in state; "No reply from {} after {:?}", ns, timeout);
+                break;
+            }
+            socket.set_read_timeout(Some(deadline - now))?;
+            let (amt, source) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                // Depending on the platform, a read timeout shows up as either of these
+                Err(ref error)
+                    if error.kind() == io::ErrorKind::WouldBlock
+                        || error.kind() == io::ErrorKind::TimedOut =>
+                {
+                    continue;
+                }
+                Err(error) => return Err(error.into()),
+            };
+
+            match check_reply(&packet, exact_case, ns, source, &buf[..amt], state) {
+                Ok(None) => continue,
+                // Could be forged, so it's ignored like any other reply that isn't quite right
+                // while the real one might still be on its way
+                Err(QueryError::CaseMismatch(_)) => case_mismatched = true,
+                Err(error) => return Err(error),
+                Ok(Some(reply)) if reply.flags.tc_bit => {
+                    // The full response didn't fit in a datagram, so what we have is only part
+                    // of it. Ask again over TCP, where there's room for all of it.
+                    log!(in state; "Reply from {} was truncated, retrying over TCP", ns);
+                    let reply = exchange_tcp(&packet, ns, options)?;
+                    return Ok(TimedReply { reply, rtt: None });
+                }
+                Ok(Some(reply)) => {
+                    // Only the first transmission's reply is a clean RTT sample
+                    let rtt = (attempt == 0).then(|| sent.elapsed());
+                    return Ok(TimedReply { reply, rtt });
+                }
+            }
+        }
+    }
+
+    Err(QueryError::Timeout(ns))
+}
+
+// Build the query packet for `question`
+pub fn build_query(question: &DnsQuestion, edns: bool, options: &QueryOptions) -> DnsPacket {
+    let flags = DnsFlags {
+        qr_bit: false,
+        opcode: DnsOpcode::Query,
+        aa_bit: false,
+        tc_bit: false,
+        rd_bit: options.recursion_desired,
+        ra_bit: false,
+        ad_bit: false,
+        cd_bit: false,
+        rcode: DnsRCode::NoError,
+    };
+    DnsPacket {
+        // rand's default generator is a CSPRNG seeded from the OS, so the ID can't be predicted
+        // from the ones we've used before
+        id: rand::random(),
+        flags,
+        // TODO is copying the question the right thing to do here? We don't _really_ need another
+        // object, we could potentially refactor packet to write bytes from references. qname is a
+        // string vector, so this is a non-trivial copy.
+        questions: vec![question.to_owned()],
+        answers: vec![],
+        nameservers: vec![],
+        addl_recs: if edns {
+            vec![opt_record(options)]
+        } else {
+            vec![]
+        },
+    }
+}
+
+// Look at a datagram that arrived from `source` while we were waiting for a reply to `query` from
+// `ns`. Returns None if it isn't our reply and should be ignored, e.g. because it's forged.
+pub fn check_reply(
+    query: &DnsPacket,
+    exact_case: bool,
+    ns: SocketAddr,
+    source: SocketAddr,
+    datagram: &[u8],
+    state: &QueryState,
+) -> Result<Option<DnsPacket>, QueryError> {
+    if source != ns {
+        log!(in state; "Ignoring datagram from {}, expected {}", source, ns);
+        return Ok(None);
+    }
+    if datagram.len() < 2 || u16::from_be_bytes([datagram[0], datagram[1]]) != query.id {
+        log!(in state; "Ignoring reply from {} with the wrong ID", source);
+        return Ok(None);
+    }
+    // Anyone who can guess the port and ID can send us garbage, so a datagram that doesn't parse
+    // is ignored like any other forgery rather than ending the exchange
+    let reply = match DnsPacket::from_bytes(datagram) {
+        Ok(reply) => reply,
+        Err(error) => {
+            log!(in state; "Ignoring unparseable reply from {}: {}", source, error);
+            return Ok(None);
+        }
+    };
+    if !reply_matches(query, &reply) {
+        log!(in state;
+            "Ignoring reply from {} that doesn't match our query",
+            source
+        );
+        return Ok(None);
+    }
+    if exact_case && reply.questions[0].qname != query.questions[0].qname {
+        return Err(QueryError::CaseMismatch(ns));
+    }
+    Ok(Some(reply))
+}
+
+// Send a query over TCP and read back the reply. Per RFC 1035 section 4.2.2 (and RFC 7766), each
+// message on a TCP connection is preceded by its length as a two byte integer.
+fn exchange_tcp(
+    query: &DnsPacket,
+    ns: SocketAddr,
+    options: &QueryOptions,
+) -> Result<DnsPacket, QueryError> {
+    let timed_out = |error: io::Error| match error.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => QueryError::Timeout(ns),
+        _ => QueryError::Io(error),
+    };
+    let mut stream = TcpStream::connect_timeout(&ns, options.max_timeout).map_err(timed_out)?;
+    stream.set_read_timeout(Some(options.max_timeout))?;
+    stream.set_write_timeout(Some(options.max_timeout))?;
+
+    stream.write_all(&tcp_message(query)).map_err(timed_out)?;
+
+    let mut length = [0; 2];
+    stream.read_exact(&mut length).map_err(timed_out)?;
+    let mut buf = vec![0; u16::from_be_bytes(length).into()];
+    stream.read_exact(&mut buf).map_err(timed_out)?;
+    check_tcp_reply(query, ns, &buf)
+}
+
+// The two byte length that goes in front of a message sent over TCP
+pub fn tcp_message(query: &DnsPacket) -> Vec<u8> {
+    let query_bytes = query.to_bytes();
+    let mut message = (query_bytes.len() as u16).to_be_bytes().to_vec();
+    message.extend(query_bytes);
+    message
+}
+
+// Nobody off-path can inject data into a TCP connection, but the server could still be confused
+pub fn check_tcp_reply(
+    query: &DnsPacket,
+    ns: SocketAddr,
+    message: &[u8],
+) -> Result<DnsPacket, QueryError> {
+    let reply = DnsPacket::from_bytes(message)?;
+    // Off-path forgers are no worry over TCP, so there's nothing for the case of the name to catch
+    if !reply_matches(query, &reply) {
+        return Err(QueryError::Mismatch(ns));
+    }
+    Ok(reply)
+}
+
+// The OPT pseudo-record we add to queries. It has no name, and its class and TTL fields are
+// repurposed: the class holds our UDP payload size, and the TTL holds the extended rcode, EDNS
+// version (both zero here) and flags, of which DO is the top bit of the bottom half.
+fn opt_record(options: &QueryOptions) -> DnsResourceRecord {
+    DnsResourceRecord {
+        name: vec![],
+        rr_type: DnsRRType::OPT,
+        class: DnsClass::EdnsPayloadSize(options.edns_payload_size),
+        ttl: if options.dnssec_ok { 0x8000 } else { 0 },
+        record: DnsRecordData::Other(vec![]),
+    }
+}
+
+// The wildcard address to send from, in the same family as the server we're sending to
+pub fn unspecified_address(ns: &SocketAddr) -> SocketAddr {
+    match ns {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    }
+}
+
+// Whether this machine can send over IPv6 at all. Checked once, the first time anyone asks.
+pub fn ipv6_available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        let available = UdpSocket::bind("[::]:0").is_ok();
+        if !available {
+            log!("IPv6 isn't available, only querying over IPv4");
+        }
+        available
+    })
+}
+
+// Check that a reply is actually a reply to our query: it needs to be marked as a response, carry
+// the same ID, and echo back the question we asked (ignoring case, which `exchange` checks
+// separately when it matters)
+fn reply_matches(query: &DnsPacket, reply: &DnsPacket) -> bool {
+    if !reply.flags.qr_bit || reply.id != query.id || reply.questions.len() != 1 {
+        return false;
+    }
+    let asked = &query.questions[0];
+    let echoed = &reply.questions[0];
+    lowercase_name(&echoed.qname) == lowercase_name(&asked.qname)
+        && echoed.qtype == asked.qtype
+        && echoed.qclass == asked.qclass
+}
+
+// Flip the case of each letter in a name at random. Servers are meant to copy the question into
+// their reply exactly, so this gives a forger one more bit per letter to guess (DNS 0x20).
+pub fn randomize_case(name: &[String]) -> Vec<String> {
+    name.iter()
+        .map(|label| {
+            label
+                .chars()
+                .map(|c| {
+                    if rand::random() {
+                        c.to_ascii_uppercase()
+                    } else {
+                        c.to_ascii_lowercase()
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Put the name back the way it was asked before anyone else sees the reply, so the randomised
+// case doesn't leak into the cache or back to clients
+pub fn restore_case(reply: &mut DnsPacket, question: &DnsQuestion) {
+    let qname = lowercase_name(&question.qname);
+    for echoed in reply.questions.iter_mut() {
+        echoed.qname = question.qname.to_owned();
+    }
+    for rr in reply
+        .answers
+        .iter_mut()
+        .chain(reply.nameservers.iter_mut())
+        .chain(reply.addl_recs.iter_mut())
+    {
+        if lowercase_name(&rr.name) == qname {
+            rr.name = question.qname.to_owned();
+        }
+    }
+}
+
+// How many replies in a row have to get the case of the name wrong before we decide a server doesn't
+// preserve it. A forger who gets everything else right would have to win that many races in a row
+// to turn off 0x20 for the server.
+pub const CASE_ATTEMPTS: usize = 3;
+
+// How long a server stays on a fallback list before we try it with the feature again
+const FALLBACK_DURATION: Duration = Duration::from_secs(60 * 60);
+
+// What we know about the servers we've been sending queries to, and whether to say what's going on
+// as we go. The resolver's queries all share one of these (see `global`); anything else sending
+// queries, like the stub resolver, keeps its own so it neither clutters the resolver's output nor
+// changes how the resolver talks to servers.
+pub struct QueryState {
+    // Servers that don't echo the query name's case back to us
+    case_fallback: FallbackList,
+    // Servers that don't understand EDNS
+    edns_fallback: FallbackList,
+    pub log: bool,
+}
+
+impl QueryState {
+    pub fn new(log: bool) -> QueryState {
+        QueryState {
+            case_fallback: FallbackList::new(),
+            edns_fallback: FallbackList::new(),
+            log,
+        }
+    }
+
+    pub fn case_fallback(&self) -> &FallbackList {
+        &self.case_fallback
+    }
+
+    pub fn edns_fallback(&self) -> &FallbackList {
+        &self.edns_fallback
+    }
+}
+
+// Servers that have shown they can't cope with one of the things we do to queries, so we leave
+// it out when talking to them for a while
+pub struct FallbackList {
+    servers: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl FallbackList {
+    fn new() -> FallbackList {
+        FallbackList {
+            servers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn insert(&self, server: SocketAddr) {
+        self.insert_at(server, Instant::now());
+    }
+
+    pub fn insert_at(&self, server: SocketAddr, now: Instant) {
+        let mut servers = self.servers.lock().unwrap();
+        servers.insert(server, now + FALLBACK_DURATION);
+    }
+
+    pub fn contains(&self, server: SocketAddr) -> bool {
+        self.contains_at(server, Instant::now())
+    }
+
+    pub fn contains_at(&self, server: SocketAddr, now: Instant) -> bool {
+        let mut servers = self.servers.lock().unwrap();
+        match servers.get(&server) {
+            Some(&expiry) if expiry > now => true,
+            Some(_) => {
+                servers.remove(&server);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+// The state the resolver's own queries share
+pub fn global() -> &'static QueryState {
+    static STATE: OnceLock<QueryState> = OnceLock::new();
+    STATE.get_or_init(|| QueryState::new(true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::protocol::*;
+    use crate::dns::recursive::testing::{question, reply_to};
+
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_ns_query() {
+        let question = question("google.com", DnsRRType::A);
+        // TODO not a great practice that this test requires a network connection
+        let ns = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 203, 230, 10)), 53);
+        let packet = query_nameserver(&question, ns, &QueryOptions::default())
+            .expect("query should have worked");
+        println!("{:?}", packet);
+    }
+
+    #[test]
+    fn retransmission_backs_off() {
+        let options = QueryOptions {
+            timeout: Duration::from_millis(100),
+            attempts: 4,
+            max_timeout: Duration::from_millis(300),
+            ..QueryOptions::default()
+        };
+        assert_eq!(
+            options.schedule(),
+            vec![
+                Duration::from_millis(100),
+                Duration::from_millis(200),
+                Duration::from_millis(300),
+                Duration::from_millis(300),
+            ]
+        );
+    }
+
+    #[test]
+    fn silent_server_times_out() {
+        // A "nameserver" that reads queries but never replies to them
+        let silent = UdpSocket::bind("127.0.0.1:0").expect("bind test socket");
+        let question = question("example.com", DnsRRType::A);
+        let options = QueryOptions {
+            timeout: Duration::from_millis(20),
+            attempts: 3,
+            max_timeout: Duration::from_millis(50),
+            ..QueryOptions::default()
+        };
+
+        let result = query_nameserver(&question, silent.local_addr().unwrap(), &options);
+        match result {
+            Err(QueryError::Timeout(server)) => assert_eq!(server, silent.local_addr().unwrap()),
+            other => panic!("Expected a timeout, got {:?}", other),
+        }
+
+        // Every attempt should have actually been sent
+        silent
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut buf = [0; 512];
+        for _ in 0..options.attempts {
+            silent.recv(&mut buf).expect("query should have arrived");
+        }
+    }
+
+    #[test]
+    fn forged_replies_are_ignored() {
+        let server = UdpSocket::bind("127.0.0.1:0").expect("bind test socket");
+        let server_address = server.local_addr().unwrap();
+        let question = question("example.com", DnsRRType::A);
+
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (amt, client) = server.recv_from(&mut buf).unwrap();
+            let query = DnsPacket::from_bytes(&buf[..amt]).unwrap();
+            let reply = |id: u16, qname: &str, rcode: DnsRCode| {
+                let mut reply = query.to_owned();
+                reply.id = id;
+                reply.flags.qr_bit = true;
+                reply.flags.rcode = rcode;
+                reply.questions[0].qname = parse_name(qname);
+                reply.to_bytes()
+            };
+
+            // Wrong transaction ID
+            let forged = reply(query.id.wrapping_add(1), "example.com", DnsRCode::NXDomain);
+            server.send_to(&forged, client).unwrap();
+            // Right ID, but from a different port than the one queried
+            let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let forged = reply(query.id, "example.com", DnsRCode::NXDomain);
+            other.send_to(&forged, client).unwrap();
+            // Right ID and source, but a different question
+            let forged = reply(query.id, "example.org", DnsRCode::NXDomain);
+            server.send_to(&forged, client).unwrap();
+            // And finally the real thing, echoing whatever case we asked in
+            let asked = query.questions[0].qname.join(".");
+            let genuine = reply(query.id, &asked, DnsRCode::NoError);
+            server.send_to(&genuine, client).unwrap();
+        });
+
+        let options = QueryOptions {
+            timeout: Duration::from_secs(2),
+            attempts: 1,
+            max_timeout: Duration::from_secs(2),
+            ..QueryOptions::default()
+        };
+        let reply =
+            query_nameserver(&question, server_address, &options).expect("should get a reply");
+        handle.join().unwrap();
+        assert_eq!(reply.flags.rcode, DnsRCode::NoError);
+        assert_eq!(reply.questions[0], question);
+    }
+
+    #[test]
+    fn unparseable_replies_are_ignored() {
+        let server = UdpSocket::bind("127.0.0.1:0").expect("bind test socket");
+        let server_address = server.local_addr().unwrap();
+        let question = question("example.com", DnsRRType::A);
+
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (amt, client) = server.recv_from(&mut buf).unwrap();
+            let query = DnsPacket::from_bytes(&buf[..amt]).unwrap();
+            // The right ID from the right place, followed by garbage
+            let mut garbage = query.id.to_be_bytes().to_vec();
+            garbage.extend_from_slice(&[0xff; 5]);
+            server.send_to(&garbage, client).unwrap();
+            server
+                .send_to(&reply_to(&query).to_bytes(), client)
+                .unwrap();
+        });
+
+        let options = QueryOptions {
+            timeout: Duration::from_secs(2),
+            attempts: 1,
+            max_timeout: Duration::from_secs(2),
+            ..QueryOptions::default()
+        };
+        let reply =
+            query_nameserver(&question, server_address, &options).expect("should get a reply");
+        handle.join().unwrap();
+        assert_eq!(reply.flags.rcode, DnsRCode::NoError);
+    }
+
+    #[test]
+    fn only_unretransmitted_replies_are_timed() {
+        let server = UdpSocket::bind("127.0.0.1:0").expect("bind test socket");
+        let server_address = server.local_addr().unwrap();
+        let question = question("example.com", DnsRRType::A);
+
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            // Answer the first query straight away
+            let (amt, client) = server.recv_from(&mut buf).unwrap();
+            let reply = reply_to(&DnsPacket::from_bytes(&buf[..amt]).unwrap());
+            server.send_to(&reply.to_bytes(), client).unwrap();
+            // Then sit on the second until it's been sent again
+            server.recv_from(&mut buf).unwrap();
+            let (amt, client) = server.recv_from(&mut buf).unwrap();
+            let reply = reply_to(&DnsPacket::from_bytes(&buf[..amt]).unwrap());
+            server.send_to(&reply.to_bytes(), client).unwrap();
+        });
+
+        let options = QueryOptions {
+            timeout: Duration::from_millis(200),
+            attempts: 2,
+            max_timeout: Duration::from_millis(200),
+            edns: false,
+            ..QueryOptions::default()
+        };
+        let first =
+            timed_query(&question, server_address, &options, global()).expect("should get a reply");
+        assert!(first.rtt.is_some());
+        let second =
+            timed_query(&question, server_address, &options, global()).expect("should get a reply");
+        assert!(second.rtt.is_none());
+        handle.join().unwrap();
+    }
+
+    fn long_question() -> DnsQuestion {
+        // Plenty of letters, so the randomised case is all but certain to differ from lowercase
+        question("casepreservation.example.com", DnsRRType::A)
+    }
+
+    #[test]
+    fn randomized_case_keeps_the_name() {
+        let question = long_question();
+        let mixed = randomize_case(&question.qname);
+        assert_eq!(lowercase_name(&mixed), question.qname);
+        assert_ne!(mixed, question.qname);
+    }
+
+    #[test]
+    fn case_mangling_server_gets_plain_queries() {
+        let server = UdpSocket::bind("127.0.0.1:0").expect("bind test socket");
+        let server_address = server.local_addr().unwrap();
+        let question = long_question();
+
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let mut names = Vec::new();
+            for _ in 0..CASE_ATTEMPTS + 1 {
+                let (amt, client) = server.recv_from(&mut buf).unwrap();
+                let query = DnsPacket::from_bytes(&buf[..amt]).unwrap();
+                names.push(query.questions[0].qname.to_owned());
+                // This server lowercases everything it sends back
+                let mut reply = query.to_owned();
+                reply.flags.qr_bit = true;
+                reply.questions[0].qname = lowercase_name(&query.questions[0].qname);
+                server.send_to(&reply.to_bytes(), client).unwrap();
+            }
+            names
+        });
+
+        // Each mixed case query waits out its whole timeout in case a reply with the right case
+        // comes along
+        let options = QueryOptions {
+            timeout: Duration::from_millis(200),
+            attempts: 1,
+            max_timeout: Duration::from_millis(200),
+            ..QueryOptions::default()
+        };
+        let reply =
+            query_nameserver(&question, server_address, &options).expect("should get a reply");
+        let names = handle.join().unwrap();
+
+        // The first queries had their case mixed up, each differently; the last went out as asked
+        for name in &names[..CASE_ATTEMPTS] {
+            assert_ne!(*name, question.qname);
+        }
+        assert_ne!(names[0], names[1]);
+        assert_eq!(names[CASE_ATTEMPTS], question.qname);
+        assert_eq!(reply.questions[0], question);
+        assert!(global().case_fallback().contains(server_address));
+    }
+
+    #[test]
+    fn case_mismatches_are_ignored() {
+        let server = UdpSocket::bind("127.0.0.1:0").expect("bind test socket");
+        let server_address = server.local_addr().unwrap();
+        let question = long_question();
+
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (amt, client) = server.recv_from(&mut buf).unwrap();
+            let query = DnsPacket::from_bytes(&buf[..amt]).unwrap();
+            // A forged reply that gets the case wrong, then the server's own
+            let mut reply = query.to_owned();
+            reply.flags.qr_bit = true;
+            let mut forged = reply.to_owned();
+            forged.questions[0].qname = lowercase_name(&query.questions[0].qname);
+            server.send_to(&forged.to_bytes(), client).unwrap();
+            server.send_to(&reply.to_bytes(), client).unwrap();
+        });
+
+        let options = QueryOptions {
+            timeout: Duration::from_secs(2),
+            attempts: 1,
+            max_timeout: Duration::from_secs(2),
+            ..QueryOptions::default()
+        };
+        let reply =
+            query_nameserver(&question, server_address, &options).expect("should get a reply");
+        handle.join().unwrap();
+        assert_eq!(reply.questions[0], question);
+        assert!(!global().case_fallback().contains(server_address));
+    }
+
+    #[test]
+    fn tcp_replies_can_change_case() {
+        let question = DnsQuestion {
+            qname: randomize_case(&long_question().qname),
+            ..long_question()
+        };
+        let query = build_query(&question, false, &QueryOptions::default());
+        let mut reply = query.to_owned();
+        reply.flags.qr_bit = true;
+        reply.questions[0].qname = lowercase_name(&question.qname);
+        let ns = "127.0.0.1:53".parse().unwrap();
+        assert!(check_tcp_reply(&query, ns, &reply.to_bytes()).is_ok());
+    }
+
+    #[test]
+    fn fallback_expires() {
+        let fallback = FallbackList::new();
+        let server = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53)), 53);
+        let now = Instant::now();
+        fallback.insert_at(server, now);
+        assert!(fallback.contains_at(server, now + Duration::from_secs(60)));
+        assert!(!fallback.contains_at(server, now + FALLBACK_DURATION));
+    }
+
+    #[test]
+    fn original_case_is_restored() {
+        let question = long_question();
+        let mut reply = DnsPacket {
+            id: 1,
+            flags: DnsFlags::from_bytes(&[0x80, 0x00]).unwrap(),
+            questions: vec![DnsQuestion {
+                qname: randomize_case(&question.qname),
+                ..question.to_owned()
+            }],
+            answers: vec![DnsResourceRecord {
+                name: randomize_case(&question.qname),
+                rr_type: DnsRRType::A,
+                class: DnsClass::IN,
+                ttl: 60,
+                record: DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            }],
+            nameservers: vec![],
+            addl_recs: vec![],
+        };
+        restore_case(&mut reply, &question);
+        assert_eq!(reply.questions[0], question);
+        assert_eq!(reply.answers[0].name, question.qname);
+    }
+
+    #[test]
+    fn queries_go_out_over_ipv6() {
+        // Not every machine has IPv6, even on loopback
+        let server = match UdpSocket::bind("[::1]:0") {
+            Ok(server) => server,
+            Err(_) => return,
+        };
+        let server_address = server.local_addr().unwrap();
+        let question = question("example.com", DnsRRType::AAAA);
+
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (amt, client) = server.recv_from(&mut buf).unwrap();
+            let reply = reply_to(&DnsPacket::from_bytes(&buf[..amt]).unwrap());
+            server.send_to(&reply.to_bytes(), client).unwrap();
+        });
+
+        let options = QueryOptions {
+            timeout: Duration::from_secs(2),
+            attempts: 1,
+            max_timeout: Duration::from_secs(2),
+            ..QueryOptions::default()
+        };
+        let reply =
+            query_nameserver(&question, server_address, &options).expect("should get a reply");
+        handle.join().unwrap();
+        assert_eq!(reply.questions[0], question);
+    }
+
+    #[test]
+    fn truncated_replies_are_retried_over_tcp() {
+        let udp = UdpSocket::bind("127.0.0.1:0").expect("bind test socket");
+        let server_address = udp.local_addr().unwrap();
+        let tcp = std::net::TcpListener::bind(server_address).expect("bind test listener");
+        let question = question("example.com", DnsRRType::A);
+
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (amt, client) = udp.recv_from(&mut buf).unwrap();
+            let mut truncated = DnsPacket::from_bytes(&buf[..amt]).unwrap();
+            truncated.flags.qr_bit = true;
+            truncated.flags.tc_bit = true;
+            udp.send_to(&truncated.to_bytes(), client).unwrap();
+
+            let (mut stream, _) = tcp.accept().unwrap();
+            let mut length = [0; 2];
+            stream.read_exact(&mut length).unwrap();
+            let mut buf = vec![0; u16::from_be_bytes(length).into()];
+            stream.read_exact(&mut buf).unwrap();
+            let mut reply = DnsPacket::from_bytes(&buf).unwrap();
+            reply.flags.qr_bit = true;
+            reply.answers.push(DnsResourceRecord {
+                name: reply.questions[0].qname.to_owned(),
+                rr_type: DnsRRType::A,
+                class: DnsClass::IN,
+                ttl: 60,
+                record: DnsRecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            });
+            let reply = reply.to_bytes();
+            // Write the length and the message separately, like a server that doesn't bother
+            // to buffer, to make sure we don't assume both arrive together
+            stream
+                .write_all(&(reply.len() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&reply).unwrap();
+        });
+
+        let options = QueryOptions {
+            timeout: Duration::from_secs(2),
+            attempts: 1,
+            max_timeout: Duration::from_secs(2),
+            ..QueryOptions::default()
+        };
+        let reply =
+            query_nameserver(&question, server_address, &options).expect("should get a reply");
+        handle.join().unwrap();
+        assert!(!reply.flags.tc_bit);
+        assert_eq!(reply.questions[0], question);
+        assert_eq!(reply.answers.len(), 1);
+    }
+
+    #[test]
+    fn queries_advertise_edns() {
+        let server = UdpSocket::bind("127.0.0.1:0").expect("bind test socket");
+        let server_address = server.local_addr().unwrap();
+        let question = question("example.com", DnsRRType::A);
+
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (amt, client) = server.recv_from(&mut buf).unwrap();
+            let query = DnsPacket::from_bytes(&buf[..amt]).unwrap();
+            server
+                .send_to(&reply_to(&query).to_bytes(), client)
+                .unwrap();
+            query
+        });
+
+        let options = QueryOptions {
+            timeout: Duration::from_secs(2),
+            attempts: 1,
+            max_timeout: Duration::from_secs(2),
+            dnssec_ok: true,
+            ..QueryOptions::default()
+        };
+        let reply =
+            query_nameserver(&question, server_address, &options).expect("should get a reply");
+        let query = handle.join().unwrap();
+
+        assert_eq!(query.addl_recs.len(), 1);
+        let opt = &query.addl_recs[0];
+        assert_eq!(opt.rr_type, DnsRRType::OPT);
+        assert_eq!(opt.class, DnsClass::EdnsPayloadSize(1232));
+        assert_eq!(opt.ttl, 0x8000);
+        // The server's OPT record isn't passed along
+        assert!(reply.addl_recs.is_empty());
+    }
+
+    #[test]
+    fn servers_without_edns_get_plain_queries() {
+        let server = UdpSocket::bind("127.0.0.1:0").expect("bind test socket");
+        let server_address = server.local_addr().unwrap();
+        let question = question("example.com", DnsRRType::A);
+
+        // A server from before EDNS, which chokes on anything in the additional section
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let mut opt_counts = Vec::new();
+            for _ in 0..2 {
+                let (amt, client) = server.recv_from(&mut buf).unwrap();
+                let query = DnsPacket::from_bytes(&buf[..amt]).unwrap();
+                opt_counts.push(query.addl_recs.len());
+                let mut reply = query.to_owned();
+                reply.flags.qr_bit = true;
+                reply.addl_recs.clear();
+                if !query.addl_recs.is_empty() {
+                    reply.flags.rcode = DnsRCode::FormError;
+                }
+                server.send_to(&reply.to_bytes(), client).unwrap();
+            }
+            opt_counts
+        });
+
+        let options = QueryOptions {
+            timeout: Duration::from_secs(2),
+            attempts: 1,
+            max_timeout: Duration::from_secs(2),
+            ..QueryOptions::default()
+        };
+        let reply =
+            query_nameserver(&question, server_address, &options).expect("should get a reply");
+        assert_eq!(handle.join().unwrap(), vec![1, 0]);
+        assert_eq!(reply.flags.rcode, DnsRCode::NoError);
+        assert!(global().edns_fallback().contains(server_address));
+    }
+}
@@ -1,11 +1,403 @@
-use std::net::{IpAddr, Ipv4Addr};
-
-// For now, this is a hardcoded list of A and AAAA records for the root nameservers
-// Information from https://www.iana.org/domains/root/servers
-// TODO pull this from configuration or directly from the OS
-pub fn get_root_nameserver() -> IpAddr {
-    // This is the A record for e.root-servers.net operated by NASA (Ames Research Center)
-    // TODO this should support V6 addresses
-    // TODO this should support returning any root nameserver
-    IpAddr::V4(Ipv4Addr::new(192, 203, 230, 10))
+// Root hints
+//
+// Every lookup that we don't have a closer delegation cached for starts at the root. We ship the
+// names and addresses of all 13 root servers (from https://www.iana.org/domains/root/servers),
+// but since those occasionally change, the same information can also be loaded from a copy of
+// the standard named.root hints file (https://www.internic.net/domain/named.root).
+//
+// The hints are only a starting point, though. Once the resolver is running it asks the roots
+// themselves for the current list (see the priming module), and uses that until its TTL runs out.
+//
+// Which root we ask first is up to the configured selection strategy: either spread queries across
+// them at random, or prefer the ones that have been answering us fastest.
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+
+use super::config;
+use super::delegation::{Delegation, Nameserver};
+use super::infra::{self, InfraCache};
+use crate::dns::protocol::{lowercase_name, parse_name};
+
+// The root servers as of the 2023 renumbering of b.root-servers.net
+const BUILTIN_ROOTS: [(&str, Ipv4Addr, Ipv6Addr); 13] = [
+    (
+        "a",
+        Ipv4Addr::new(198, 41, 0, 4),
+        Ipv6Addr::new(0x2001, 0x503, 0xba3e, 0, 0, 0, 0x2, 0x30),
+    ),
+    (
+        "b",
+        Ipv4Addr::new(170, 247, 170, 2),
+        Ipv6Addr::new(0x2801, 0x1b8, 0x10, 0, 0, 0, 0, 0xb),
+    ),
+    (
+        "c",
+        Ipv4Addr::new(192, 33, 4, 12),
+        Ipv6Addr::new(0x2001, 0x500, 0x2, 0, 0, 0, 0, 0xc),
+    ),
+    (
+        "d",
+        Ipv4Addr::new(199, 7, 91, 13),
+        Ipv6Addr::new(0x2001, 0x500, 0x2d, 0, 0, 0, 0, 0xd),
+    ),
+    (
+        "e",
+        Ipv4Addr::new(192, 203, 230, 10),
+        Ipv6Addr::new(0x2001, 0x500, 0xa8, 0, 0, 0, 0, 0xe),
+    ),
+    (
+        "f",
+        Ipv4Addr::new(192, 5, 5, 241),
+        Ipv6Addr::new(0x2001, 0x500, 0x2f, 0, 0, 0, 0, 0xf),
+    ),
+    (
+        "g",
+        Ipv4Addr::new(192, 112, 36, 4),
+        Ipv6Addr::new(0x2001, 0x500, 0x12, 0, 0, 0, 0, 0xd0d),
+    ),
+    (
+        "h",
+        Ipv4Addr::new(198, 97, 190, 53),
+        Ipv6Addr::new(0x2001, 0x500, 0x1, 0, 0, 0, 0, 0x53),
+    ),
+    (
+        "i",
+        Ipv4Addr::new(192, 36, 148, 17),
+        Ipv6Addr::new(0x2001, 0x7fe, 0, 0, 0, 0, 0, 0x53),
+    ),
+    (
+        "j",
+        Ipv4Addr::new(192, 58, 128, 30),
+        Ipv6Addr::new(0x2001, 0x503, 0xc27, 0, 0, 0, 0x2, 0x30),
+    ),
+    (
+        "k",
+        Ipv4Addr::new(193, 0, 14, 129),
+        Ipv6Addr::new(0x2001, 0x7fd, 0, 0, 0, 0, 0, 0x1),
+    ),
+    (
+        "l",
+        Ipv4Addr::new(199, 7, 83, 42),
+        Ipv6Addr::new(0x2001, 0x500, 0x9f, 0, 0, 0, 0, 0x42),
+    ),
+    (
+        "m",
+        Ipv4Addr::new(202, 12, 27, 33),
+        Ipv6Addr::new(0x2001, 0xdc3, 0, 0, 0, 0, 0, 0x35),
+    ),
+];
+
+// How to order the root servers when starting a lookup
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RootSelection {
+    // Shuffle them, spreading our queries evenly
+    #[default]
+    Random,
+    // Fastest first, based on how long they've taken to answer us. Roots we haven't heard from
+    // yet go to the front so that every one gets measured.
+    Rtt,
+}
+
+impl FromStr for RootSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RootSelection, String> {
+        match s {
+            "random" => Ok(RootSelection::Random),
+            "rtt" => Ok(RootSelection::Rtt),
+            _ => Err(format!("Unknown root selection {}", s)),
+        }
+    }
+}
+
+pub struct RootHints {
+    nameservers: Vec<Nameserver>,
+    // The root servers we learned from the last successful priming query, and when that list
+    // expires. Until then, it's used in place of the hints.
+    primed: Mutex<Option<(Vec<Nameserver>, Instant)>>,
+}
+
+impl RootHints {
+    fn new(nameservers: Vec<Nameserver>) -> RootHints {
+        RootHints {
+            nameservers,
+            primed: Mutex::new(None),
+        }
+    }
+
+    // The hints compiled into the resolver
+    pub fn builtin() -> RootHints {
+        let nameservers = BUILTIN_ROOTS
+            .iter()
+            .map(|(letter, v4, v6)| Nameserver {
+                name: vec![
+                    (*letter).to_owned(),
+                    "root-servers".to_owned(),
+                    "net".to_owned(),
+                ],
+                addresses: vec![IpAddr::V4(*v4), IpAddr::V6(*v6)],
+            })
+            .collect();
+        RootHints::new(nameservers)
+    }
+
+    pub fn load(path: &Path) -> Result<RootHints, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|error| format!("Couldn't read root hints {}: {}", path.display(), error))?;
+        RootHints::parse(&contents)
+    }
+
+    // Parse hints in the master file format used by named.root: NS records for the root, plus A
+    // and AAAA records for each of the servers they name. The TTL and class columns are optional,
+    // as they are in any zone file. Anything else in the file is ignored.
+    pub fn parse(contents: &str) -> Result<RootHints, String> {
+        let mut nameservers: Vec<Nameserver> = Vec::new();
+        let mut addresses: HashMap<Vec<String>, Vec<IpAddr>> = HashMap::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = match line.find(';') {
+                Some(comment) => &line[..comment],
+                None => line,
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let bad_line = || format!("Bad root hints line {}: {}", number + 1, line.trim());
+            if fields.len() < 3 {
+                return Err(bad_line());
+            }
+            let name = lowercase_name(&parse_name(fields[0]));
+            let rr_type = fields[fields.len() - 2].to_ascii_uppercase();
+            let data = fields[fields.len() - 1];
+            match rr_type.as_str() {
+                "NS" => {
+                    if !name.is_empty() {
+                        return Err(bad_line());
+                    }
+                    nameservers.push(Nameserver {
+                        name: lowercase_name(&parse_name(data)),
+                        addresses: vec![],
+                    });
+                }
+                "A" | "AAAA" => {
+                    let address = match rr_type.as_str() {
+                        "A" => data.parse::<Ipv4Addr>().map(IpAddr::V4),
+                        _ => data.parse::<Ipv6Addr>().map(IpAddr::V6),
+                    }
+                    .map_err(|_| bad_line())?;
+                    addresses.entry(name).or_default().push(address);
+                }
+                _ => (),
+            }
+        }
+
+        for ns in nameservers.iter_mut() {
+            if let Some(found) = addresses.remove(&lowercase_name(&ns.name)) {
+                ns.addresses = found;
+            }
+        }
+        nameservers.retain(|ns| !ns.addresses.is_empty());
+        if nameservers.is_empty() {
+            return Err("Root hints don't contain any root servers with addresses".to_owned());
+        }
+        Ok(RootHints::new(nameservers))
+    }
+
+    // The root servers from the hints file (or builtin list), ignoring anything learned by priming
+    pub fn hinted(&self) -> &[Nameserver] {
+        &self.nameservers
+    }
+
+    // Replace the root servers with the ones from a priming response, good for `ttl` seconds
+    pub fn set_primed(&self, nameservers: Vec<Nameserver>, ttl: u32) {
+        self.set_primed_at(nameservers, ttl, Instant::now());
+    }
+
+    pub fn set_primed_at(&self, nameservers: Vec<Nameserver>, ttl: u32, now: Instant) {
+        let expiry = now + Duration::from_secs(ttl.into());
+        *self.primed.lock().unwrap() = Some((nameservers, expiry));
+    }
+
+    // The root servers we should be using right now: the primed ones if we have them, otherwise
+    // the hints
+    pub fn current_at(&self, now: Instant) -> Vec<Nameserver> {
+        let mut primed = self.primed.lock().unwrap();
+        match &*primed {
+            Some((nameservers, expiry)) if *expiry > now => nameservers.to_owned(),
+            Some(_) => {
+                log!("Primed root servers have expired, falling back to root hints");
+                *primed = None;
+                self.nameservers.to_owned()
+            }
+            None => self.nameservers.to_owned(),
+        }
+    }
+
+    // The root zone's delegation, with the servers ordered according to `selection`
+    pub fn delegation(&self, selection: RootSelection) -> Delegation {
+        self.delegation_at(selection, infra::global(), Instant::now())
+    }
+
+    pub fn delegation_at(
+        &self,
+        selection: RootSelection,
+        infra: &InfraCache,
+        now: Instant,
+    ) -> Delegation {
+        let mut nameservers = self.current_at(now);
+        nameservers.shuffle(&mut rand::thread_rng());
+        if selection == RootSelection::Rtt {
+            // The sort is stable, so servers that tie stay shuffled
+            nameservers.sort_by_key(|ns| {
+                ns.addresses
+                    .iter()
+                    .map(|address| infra.srtt(*address).unwrap_or_default())
+                    .min()
+                    .unwrap_or_default()
+            });
+        }
+        Delegation {
+            zone: vec![],
+            nameservers,
+        }
+    }
+}
+
+static HINTS: OnceLock<RootHints> = OnceLock::new();
+
+// Replace the builtin hints, e.g. with ones loaded from a file. Like the rest of the resolver
+// configuration, this has to happen before the first lookup.
+pub fn install(hints: RootHints) -> Result<(), String> {
+    HINTS
+        .set(hints)
+        .map_err(|_| "Root hints were already set".to_owned())
+}
+
+pub fn hints() -> &'static RootHints {
+    HINTS.get_or_init(RootHints::builtin)
+}
+
+// The root zone's delegation, used as a starting point when we don't know anything closer
+pub fn get_root_delegation() -> Delegation {
+    hints().delegation(config::get().root_selection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMED_ROOT: &str = "
+;       This file holds the information on root name servers needed to
+;       initialize cache of Internet domain name servers
+;
+; FORMERLY NS.INTERNIC.NET
+;
+.                        3600000      NS    A.ROOT-SERVERS.NET.
+A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4
+A.ROOT-SERVERS.NET.      3600000      AAAA  2001:503:ba3e::2:30
+;
+; FORMERLY NS1.ISI.EDU
+;
+.                        3600000      NS    B.ROOT-SERVERS.NET.
+B.ROOT-SERVERS.NET.      3600000  IN  A     170.247.170.2
+B.ROOT-SERVERS.NET.                   AAAA  2801:1b8:10::b
+; END OF FILE
+";
+
+    #[test]
+    fn builtin_hints_have_every_root() {
+        let delegation = RootHints::builtin().delegation(RootSelection::Random);
+        assert!(delegation.zone.is_empty());
+        assert_eq!(delegation.nameservers.len(), 13);
+        for ns in &delegation.nameservers {
+            assert!(ns.addresses.iter().any(|address| address.is_ipv4()));
+            assert!(ns.addresses.iter().any(|address| address.is_ipv6()));
+        }
+    }
+
+    #[test]
+    fn named_root_is_parsed() {
+        let hints = RootHints::parse(NAMED_ROOT).expect("hints should parse");
+        assert_eq!(hints.nameservers.len(), 2);
+        assert_eq!(
+            hints.nameservers[0].name,
+            vec!["a".to_owned(), "root-servers".to_owned(), "net".to_owned()]
+        );
+        assert_eq!(
+            hints.nameservers[1].addresses,
+            vec![
+                IpAddr::V4(Ipv4Addr::new(170, 247, 170, 2)),
+                IpAddr::V6(Ipv6Addr::new(0x2801, 0x1b8, 0x10, 0, 0, 0, 0, 0xb)),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_hints_are_rejected() {
+        assert!(RootHints::parse("; nothing but a comment\n").is_err());
+        assert!(RootHints::parse(". 3600000 NS A.ROOT-SERVERS.NET.\n").is_err());
+        assert!(RootHints::parse("A.ROOT-SERVERS.NET. 3600000 A not-an-address\n").is_err());
+    }
+
+    #[test]
+    fn fastest_root_goes_first() {
+        let hints = RootHints::parse(NAMED_ROOT).unwrap();
+        let infra = InfraCache::new();
+        let a = IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4));
+        let b = IpAddr::V4(Ipv4Addr::new(170, 247, 170, 2));
+        let b_v6 = IpAddr::V6(Ipv6Addr::new(0x2801, 0x1b8, 0x10, 0, 0, 0, 0, 0xb));
+        infra.record_rtt(a, Duration::from_millis(20));
+        infra.record_rtt(b, Duration::from_millis(200));
+        infra.record_rtt(b_v6, Duration::from_millis(100));
+        for _ in 0..10 {
+            let delegation = hints.delegation_at(RootSelection::Rtt, &infra, Instant::now());
+            assert_eq!(delegation.nameservers[0].name[0], "a");
+        }
+
+        // Smoothing means one slow reply only nudges the average
+        infra.record_rtt(a, Duration::from_millis(420));
+        assert_eq!(infra.srtt(a), Some(Duration::from_millis(70)));
+        assert_eq!(
+            hints
+                .delegation_at(RootSelection::Rtt, &infra, Instant::now())
+                .nameservers[0]
+                .name[0],
+            "a"
+        );
+    }
+
+    #[test]
+    fn primed_roots_replace_hints_until_they_expire() {
+        let hints = RootHints::parse(NAMED_ROOT).unwrap();
+        let primed = vec![Nameserver {
+            name: vec!["z".to_owned(), "root-servers".to_owned(), "net".to_owned()],
+            addresses: vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))],
+        }];
+        let now = Instant::now();
+        hints.set_primed_at(primed.to_owned(), 60, now);
+        assert_eq!(
+            hints
+                .delegation_at(RootSelection::Random, &InfraCache::new(), now)
+                .nameservers,
+            primed
+        );
+
+        let later = now + Duration::from_secs(60);
+        assert_eq!(
+            hints
+                .delegation_at(RootSelection::Random, &InfraCache::new(), later)
+                .nameservers
+                .len(),
+            2
+        );
+    }
 }
@@ -0,0 +1,20 @@
+// Fixtures shared by the resolver's tests
+
+use crate::dns::protocol::{parse_name, DnsClass, DnsPacket, DnsQuestion, DnsRRType};
+
+pub fn question(name: &str, qtype: DnsRRType) -> DnsQuestion {
+    DnsQuestion {
+        qname: parse_name(name),
+        qtype,
+        qclass: DnsClass::IN,
+    }
+}
+
+// An authoritative reply to `query`, echoing its question with nothing else in it
+pub fn reply_to(query: &DnsPacket) -> DnsPacket {
+    let mut reply = query.to_owned();
+    reply.flags.qr_bit = true;
+    reply.flags.aa_bit = true;
+    reply.addl_recs.clear();
+    reply
+}
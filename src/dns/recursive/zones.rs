@@ -0,0 +1,205 @@
+// Per-domain routing rules
+//
+// Some names can't be found by starting at the root: an internal domain like "corp.internal" only
+// exists on a company's own servers. Each rule names a domain and says what to do instead for
+// names in it:
+//  - a forward zone sends questions to the given resolvers, with RD set, and takes their answer
+//  - a stub zone starts iterating at the given authoritative servers for the domain, as if the
+//    parent had referred us there
+// When rules overlap, the one for the longest matching domain wins, so "lab.corp.internal" can be
+// handled differently to the rest of "corp.internal". Names that don't match any rule are
+// resolved as usual.
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use super::bailiwick::in_bailiwick;
+use super::config;
+use super::delegation::{Delegation, Nameserver};
+use super::forward::{ForwardStrategy, UpstreamPool};
+use crate::dns::protocol::{lowercase_name, parse_name};
+
+// A domain and the servers to use for it, as given on the command line: "corp.internal=10.0.0.1",
+// with more servers separated by commas
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZoneServers {
+    pub zone: Vec<String>,
+    pub servers: Vec<SocketAddr>,
+}
+
+impl FromStr for ZoneServers {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ZoneServers, String> {
+        let (zone, servers) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected <zone>=<server>[,<server>...], got {}", s))?;
+        let servers = servers
+            .split(',')
+            .map(parse_server_address)
+            .collect::<Result<Vec<SocketAddr>, String>>()?;
+        Ok(ZoneServers {
+            zone: lowercase_name(&parse_name(zone)),
+            servers,
+        })
+    }
+}
+
+// An address and port like "192.0.2.1:5353" or "[2001:db8::1]:53", or just an address, which
+// gets the standard DNS port
+pub fn parse_server_address(value: &str) -> Result<SocketAddr, String> {
+    value
+        .parse()
+        .or_else(|_| value.parse().map(|address| SocketAddr::new(address, 53)))
+        .map_err(|_| format!("Invalid server address {}", value))
+}
+
+pub enum Action {
+    Forward(UpstreamPool),
+    // Where iteration starts for names in the zone
+    Stub(Delegation),
+}
+
+pub struct Rule {
+    pub zone: Vec<String>,
+    pub action: Action,
+}
+
+pub struct ZoneRules {
+    rules: Vec<Rule>,
+}
+
+impl ZoneRules {
+    // Forward zones pick among their upstreams using `strategy`
+    pub fn new(
+        forward_zones: &[ZoneServers],
+        stub_zones: &[ZoneServers],
+        strategy: ForwardStrategy,
+    ) -> ZoneRules {
+        let forwards = forward_zones.iter().map(|forward| Rule {
+            zone: lowercase_name(&forward.zone),
+            action: Action::Forward(UpstreamPool::new(&forward.servers, strategy)),
+        });
+        let stubs = stub_zones.iter().map(|stub| Rule {
+            zone: lowercase_name(&stub.zone),
+            action: Action::Stub(stub_delegation(stub)),
+        });
+        ZoneRules {
+            rules: forwards.chain(stubs).collect(),
+        }
+    }
+
+    // The rule for the closest enclosing domain of `name`, if there is one. On a tie, forward
+    // zones win over stub zones.
+    pub fn find(&self, name: &[String]) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .filter(|rule| in_bailiwick(name, &rule.zone))
+            .fold(None, |best: Option<&Rule>, rule| match best {
+                Some(best) if best.zone.len() >= rule.zone.len() => Some(best),
+                _ => Some(rule),
+            })
+    }
+
+    // The upstream pools of every forward zone, to keep an eye on their health
+    pub fn pools(&self) -> impl Iterator<Item = &UpstreamPool> {
+        self.rules.iter().filter_map(|rule| match &rule.action {
+            Action::Forward(pool) => Some(pool),
+            Action::Stub(_) => None,
+        })
+    }
+}
+
+// The servers for a stub zone don't have names we know of, just addresses, so each address
+// becomes a nameserver of its own
+fn stub_delegation(stub: &ZoneServers) -> Delegation {
+    Delegation {
+        zone: stub.zone.to_owned(),
+        nameservers: stub
+            .servers
+            .iter()
+            .map(|server| Nameserver {
+                name: stub_nameserver_name(server.ip()),
+                addresses: vec![server.ip()],
+            })
+            .collect(),
+    }
+}
+
+// A made up name for a stub zone's server, only there because every nameserver needs one. It goes
+// under "invalid" (RFC 6761), which can never exist, so nothing will ever try to look it up or
+// mistake it for a real server: "10-0-1-1.invalid", or "2001-db8--1.invalid".
+fn stub_nameserver_name(address: IpAddr) -> Vec<String> {
+    let label = address.to_string().replace(['.', ':'], "-");
+    vec![label, "invalid".to_owned()]
+}
+
+// The rules from the configuration
+pub fn global() -> &'static ZoneRules {
+    static RULES: OnceLock<ZoneRules> = OnceLock::new();
+    RULES.get_or_init(|| {
+        let config = config::get();
+        ZoneRules::new(
+            &config.forward_zones,
+            &config.stub_zones,
+            config.forward_strategy,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn zone_servers_are_parsed() {
+        let parsed: ZoneServers = "Corp.Internal.=10.0.0.1,10.0.0.2:5353".parse().unwrap();
+        assert_eq!(parsed.zone, parse_name("corp.internal"));
+        assert_eq!(
+            parsed.servers,
+            vec![
+                "10.0.0.1:53".parse().unwrap(),
+                "10.0.0.2:5353".parse().unwrap()
+            ]
+        );
+
+        assert!("corp.internal".parse::<ZoneServers>().is_err());
+        assert!("corp.internal=server".parse::<ZoneServers>().is_err());
+    }
+
+    #[test]
+    fn longest_suffix_wins() {
+        let rules = ZoneRules::new(
+            &["corp.internal=10.0.0.1".parse().unwrap()],
+            &["lab.corp.internal=10.0.1.1".parse().unwrap()],
+            ForwardStrategy::StrictOrder,
+        );
+
+        let rule = rules.find(&parse_name("www.corp.internal")).unwrap();
+        assert_eq!(rule.zone, parse_name("corp.internal"));
+        assert!(matches!(rule.action, Action::Forward(_)));
+
+        let rule = rules.find(&parse_name("host.LAB.corp.internal")).unwrap();
+        match &rule.action {
+            Action::Stub(delegation) => {
+                assert_eq!(
+                    delegation.addresses(),
+                    vec![IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1))]
+                );
+                assert_eq!(
+                    delegation.nameservers[0].name,
+                    parse_name("10-0-1-1.invalid")
+                );
+            }
+            Action::Forward(_) => panic!("Expected the stub zone"),
+        }
+
+        // Everything else is recursed as usual
+        assert!(rules.find(&parse_name("www.example.com")).is_none());
+        assert!(rules.find(&parse_name("notcorp.internal")).is_none());
+        assert_eq!(rules.pools().count(), 1);
+    }
+}
@@ -0,0 +1,272 @@
+// Stub resolver
+//
+// For programs that want to look things up rather than run a resolver of their own. Like the
+// system's resolver, this hands every question to the recursive resolvers in resolv.conf with RD
+// set, and lets them do the work. It uses the same query code as the recursive resolver, so
+// queries get random IDs, source ports and name case, replies are checked the same way, and a
+// truncated reply is retried over TCP. It keeps quiet about it, though, and has its own list of
+// servers that need EDNS or 0x20 left out, separate from the resolver's.
+//
+// Names that aren't fully qualified (no trailing dot) are tried with each domain in the search
+// list appended, following the same rules as the system resolver (see resolv.conf(5)).
+
+mod resolv_conf;
+
+pub use resolv_conf::ResolvConf;
+
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::protocol::{parse_name, DnsClass, DnsPacket, DnsQuestion, DnsRCode, DnsRRType};
+use super::recursive::{query_with, QueryOptions, QueryState};
+
+// Errors are Send and Sync so lookups can be done from any thread
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+pub struct StubResolver {
+    conf: ResolvConf,
+    // Which nameserver to start at next, when rotating
+    next: AtomicUsize,
+    state: QueryState,
+}
+
+impl StubResolver {
+    pub fn new(conf: ResolvConf) -> StubResolver {
+        StubResolver {
+            conf,
+            next: AtomicUsize::new(0),
+            state: QueryState::new(false),
+        }
+    }
+
+    // A resolver using the system's /etc/resolv.conf
+    pub fn from_system() -> std::result::Result<StubResolver, String> {
+        Ok(StubResolver::new(ResolvConf::system()?))
+    }
+
+    pub fn conf(&self) -> &ResolvConf {
+        &self.conf
+    }
+
+    // Look up `name`, applying the search list. The first response with records in it wins. If
+    // none of the names we try have any, the response for the name as given is returned.
+    pub fn query(&self, name: &str, qtype: DnsRRType) -> Result<DnsPacket> {
+        let mut as_given = None;
+        let mut last_error = None;
+        for candidate in self.candidates(name) {
+            let question = DnsQuestion {
+                qname: candidate.to_owned(),
+                qtype,
+                qclass: DnsClass::IN,
+            };
+            match self.query_question(&question) {
+                Ok(response) => {
+                    if response.flags.rcode == DnsRCode::NoError && !response.answers.is_empty() {
+                        return Ok(response);
+                    }
+                    if candidate == parse_name(name) {
+                        as_given = Some(response);
+                    }
+                }
+                Err(error) => last_error = Some(error),
+            }
+        }
+        match (as_given, last_error) {
+            (Some(response), _) => Ok(response),
+            (None, Some(error)) => Err(error),
+            (None, None) => Err(format!("No answer for {}", name).into()),
+        }
+    }
+
+    // Send a question as it is to the nameservers. Each gets `timeout` to answer before we move on
+    // to the next, and we go round them all `attempts` times before giving up. A server that
+    // answers SERVFAIL or REFUSED is treated like one that didn't answer.
+    pub fn query_question(&self, question: &DnsQuestion) -> Result<DnsPacket> {
+        let options = QueryOptions {
+            timeout: self.conf.timeout,
+            max_timeout: self.conf.timeout,
+            attempts: 1,
+            recursion_desired: true,
+            ..QueryOptions::default()
+        };
+        let nameservers = &self.conf.nameservers;
+        let start = if self.conf.rotate {
+            self.next.fetch_add(1, Ordering::Relaxed)
+        } else {
+            0
+        };
+        let mut last_error: Box<dyn Error + Send + Sync> = "No nameservers configured".into();
+        for _ in 0..self.conf.attempts {
+            for i in 0..nameservers.len() {
+                let server = nameservers[(start + i) % nameservers.len()];
+                match query_with(question, server, &options, &self.state) {
+                    Ok(reply)
+                        if reply.flags.rcode == DnsRCode::ServFail
+                            || reply.flags.rcode == DnsRCode::Refused =>
+                    {
+                        last_error = format!("Got {:?} from {}", reply.flags.rcode, server).into();
+                    }
+                    Ok(reply) => return Ok(reply),
+                    Err(error) => last_error = error.into(),
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    // The names to try for `name`, in order. A name with a trailing dot is only tried as it is.
+    // Otherwise, one with at least `ndots` dots is tried as it is first and then with the search
+    // domains, and one with fewer gets the search domains first.
+    pub fn candidates(&self, name: &str) -> Vec<Vec<String>> {
+        let as_given = parse_name(name);
+        if name.ends_with('.') {
+            return vec![as_given];
+        }
+        let searched = self.conf.search.iter().map(|domain| {
+            let mut candidate = as_given.to_owned();
+            candidate.extend(parse_name(domain));
+            candidate
+        });
+        let dots = name.matches('.').count() as u32;
+        if dots >= self.conf.ndots {
+            std::iter::once(as_given.to_owned())
+                .chain(searched)
+                .collect()
+        } else {
+            searched
+                .chain(std::iter::once(as_given.to_owned()))
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::protocol::*;
+
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    fn with_search(search: &[&str], ndots: u32) -> StubResolver {
+        StubResolver::new(ResolvConf {
+            search: search.iter().map(|domain| domain.to_string()).collect(),
+            ndots,
+            ..ResolvConf::default()
+        })
+    }
+
+    #[test]
+    fn search_list_is_applied() {
+        let resolver = with_search(&["corp.example.com", "example.com"], 1);
+        assert_eq!(
+            resolver.candidates("www"),
+            vec![
+                parse_name("www.corp.example.com"),
+                parse_name("www.example.com"),
+                parse_name("www"),
+            ]
+        );
+        assert_eq!(
+            resolver.candidates("www.example.org"),
+            vec![
+                parse_name("www.example.org"),
+                parse_name("www.example.org.corp.example.com"),
+                parse_name("www.example.org.example.com"),
+            ]
+        );
+        assert_eq!(
+            resolver.candidates("www.example.org."),
+            vec![parse_name("www.example.org")]
+        );
+
+        let resolver = with_search(&["example.com"], 3);
+        assert_eq!(resolver.candidates("a.b")[0], parse_name("a.b.example.com"));
+    }
+
+    #[test]
+    fn queries_ask_for_recursion_and_move_on_to_the_next_name() {
+        // A resolver that only knows about www.example.com
+        let server = UdpSocket::bind("127.0.0.1:0").expect("bind test socket");
+        let conf = ResolvConf {
+            nameservers: vec![server.local_addr().unwrap()],
+            search: vec!["corp.example.com".to_owned(), "example.com".to_owned()],
+            timeout: Duration::from_secs(2),
+            ..ResolvConf::default()
+        };
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            let mut asked = Vec::new();
+            for _ in 0..2 {
+                let (amt, client) = server.recv_from(&mut buf).unwrap();
+                let query = DnsPacket::from_bytes(&buf[..amt]).unwrap();
+                assert!(query.flags.rd_bit);
+                let qname = lowercase_name(&query.questions[0].qname);
+                let mut reply = query.to_owned();
+                reply.flags.qr_bit = true;
+                reply.flags.ra_bit = true;
+                reply.addl_recs.clear();
+                if qname == parse_name("www.example.com") {
+                    reply.answers = vec![DnsResourceRecord {
+                        name: query.questions[0].qname.to_owned(),
+                        rr_type: DnsRRType::A,
+                        class: DnsClass::IN,
+                        ttl: 300,
+                        record: DnsRecordData::A("192.0.2.1".parse().unwrap()),
+                    }];
+                } else {
+                    reply.flags.rcode = DnsRCode::NXDomain;
+                }
+                server.send_to(&reply.to_bytes(), client).unwrap();
+                asked.push(qname);
+            }
+            asked
+        });
+
+        let response = StubResolver::new(conf)
+            .query("www", DnsRRType::A)
+            .expect("should get an answer");
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![
+                parse_name("www.corp.example.com"),
+                parse_name("www.example.com")
+            ]
+        );
+    }
+
+    #[test]
+    fn fallbacks_are_kept_to_the_stub() {
+        // A resolver too old to know about EDNS
+        let server = UdpSocket::bind("127.0.0.1:0").expect("bind test socket");
+        let address = server.local_addr().unwrap();
+        let conf = ResolvConf {
+            nameservers: vec![address],
+            timeout: Duration::from_secs(2),
+            ..ResolvConf::default()
+        };
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0; 512];
+            for _ in 0..2 {
+                let (amt, client) = server.recv_from(&mut buf).unwrap();
+                let query = DnsPacket::from_bytes(&buf[..amt]).unwrap();
+                let mut reply = query.to_owned();
+                reply.flags.qr_bit = true;
+                if !query.addl_recs.is_empty() {
+                    reply.flags.rcode = DnsRCode::FormError;
+                    reply.addl_recs.clear();
+                }
+                server.send_to(&reply.to_bytes(), client).unwrap();
+            }
+        });
+
+        let resolver = StubResolver::new(conf);
+        let response = resolver
+            .query("www.example.com.", DnsRRType::A)
+            .expect("should get an answer");
+        handle.join().unwrap();
+        assert_eq!(response.flags.rcode, DnsRCode::NoError);
+        assert!(resolver.state.edns_fallback().contains(address));
+    }
+}
@@ -0,0 +1,165 @@
+// Parsing /etc/resolv.conf
+//
+// The format is described in resolv.conf(5). We understand the parts a stub resolver needs:
+// "nameserver", "search" and "domain", and the "ndots", "timeout", "attempts" and "rotate"
+// options. Anything else is ignored, like the system resolver does, and so are values it would
+// reject. Limits and defaults are the same as glibc's.
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+
+// glibc only uses the first three nameservers (MAXNS)
+pub const MAX_NAMESERVERS: usize = 3;
+
+const MAX_NDOTS: u32 = 15;
+const MAX_TIMEOUT: u64 = 30;
+const MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolvConf {
+    pub nameservers: Vec<SocketAddr>,
+    // Domains to try appending to names that aren't fully qualified
+    pub search: Vec<String>,
+    // Names with at least this many dots are tried as they are before the search list
+    pub ndots: u32,
+    // How long to wait for each nameserver to answer
+    pub timeout: Duration,
+    // How many times to go through the whole list of nameservers
+    pub attempts: u32,
+    // Whether to spread queries across the nameservers rather than always starting at the first
+    pub rotate: bool,
+}
+
+impl Default for ResolvConf {
+    fn default() -> ResolvConf {
+        ResolvConf {
+            // With no nameservers configured, the system resolver asks the local machine
+            nameservers: vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 53)],
+            search: vec![],
+            ndots: 1,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            rotate: false,
+        }
+    }
+}
+
+impl ResolvConf {
+    pub fn load(path: &Path) -> Result<ResolvConf, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|error| format!("Couldn't read {}: {}", path.display(), error))?;
+        Ok(ResolvConf::parse(&contents))
+    }
+
+    // The system's configuration
+    pub fn system() -> Result<ResolvConf, String> {
+        ResolvConf::load(Path::new("/etc/resolv.conf"))
+    }
+
+    pub fn parse(contents: &str) -> ResolvConf {
+        let mut conf = ResolvConf::default();
+        let mut nameservers = Vec::new();
+        for line in contents.lines() {
+            // Comments start with either of these, and run to the end of the line
+            let line = line.split(['#', ';']).next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some("nameserver") => {
+                    // Link-local IPv6 addresses can carry a zone ("fe80::1%eth0"), which we
+                    // can't do anything with
+                    let address = fields.next().and_then(|address| address.parse().ok());
+                    if let Some(address) = address {
+                        if nameservers.len() < MAX_NAMESERVERS {
+                            nameservers.push(SocketAddr::new(address, 53));
+                        }
+                    }
+                }
+                // Whichever of these comes last wins
+                Some("search") => {
+                    conf.search = fields.map(|domain| domain.to_owned()).collect();
+                }
+                Some("domain") => {
+                    conf.search = fields.next().map(|d| d.to_owned()).into_iter().collect();
+                }
+                Some("options") => {
+                    for option in fields {
+                        conf.set_option(option);
+                    }
+                }
+                _ => (),
+            }
+        }
+        if !nameservers.is_empty() {
+            conf.nameservers = nameservers;
+        }
+        conf
+    }
+
+    fn set_option(&mut self, option: &str) {
+        let (name, value) = match option.split_once(':') {
+            Some((name, value)) => (name, value.parse::<u32>().ok()),
+            None => (option, None),
+        };
+        match (name, value) {
+            ("ndots", Some(ndots)) => self.ndots = ndots.min(MAX_NDOTS),
+            ("timeout", Some(timeout)) => {
+                self.timeout = Duration::from_secs(u64::from(timeout).clamp(1, MAX_TIMEOUT))
+            }
+            ("attempts", Some(attempts)) => self.attempts = attempts.clamp(1, MAX_ATTEMPTS),
+            ("rotate", None) => self.rotate = true,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolv_conf_is_parsed() {
+        let conf = ResolvConf::parse(
+            "# Generated by NetworkManager
+domain example.net
+search corp.example.com example.com
+nameserver 192.0.2.53
+nameserver 2001:db8::53 ; the IPv6 one
+nameserver fe80::1%eth0
+options ndots:2 timeout:3 attempts:9 rotate edns0
+",
+        );
+        assert_eq!(
+            conf,
+            ResolvConf {
+                nameservers: vec![
+                    "192.0.2.53:53".parse().unwrap(),
+                    "[2001:db8::53]:53".parse().unwrap()
+                ],
+                search: vec!["corp.example.com".to_owned(), "example.com".to_owned()],
+                ndots: 2,
+                timeout: Duration::from_secs(3),
+                attempts: MAX_ATTEMPTS,
+                rotate: true,
+            }
+        );
+    }
+
+    #[test]
+    fn missing_settings_get_defaults() {
+        let conf = ResolvConf::parse("options timeout:0\n");
+        assert_eq!(conf.nameservers, ResolvConf::default().nameservers);
+        assert_eq!(conf.timeout, Duration::from_secs(1));
+        assert_eq!(conf.ndots, 1);
+        assert!(!conf.rotate);
+
+        let nameservers: String = (1..=5)
+            .map(|i| format!("nameserver 192.0.2.{}\n", i))
+            .collect();
+        assert_eq!(
+            ResolvConf::parse(&nameservers).nameservers.len(),
+            MAX_NAMESERVERS
+        );
+    }
+}
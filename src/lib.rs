@@ -0,0 +1,9 @@
+// DNS record types, classes, and rcodes are conventionally written as their
+// all-caps mnemonics (AAAA, CNAME, NXDOMAIN...), so we keep them that way.
+// DnsFormatError carries a partial packet so we can build FormErr responses,
+// which makes it large; boxing it everywhere isn't worth the noise.
+#![allow(clippy::upper_case_acronyms, clippy::result_large_err)]
+
+// The server lives in main.rs; everything it's built from is here, so other programs can use the
+// protocol types and resolvers too.
+pub mod dns;
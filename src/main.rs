@@ -1,21 +1,75 @@
+use std::env;
 use std::error;
+use std::fmt;
+#[cfg(not(feature = "async"))]
 use std::net;
+#[cfg(not(feature = "async"))]
 use std::thread;
+use std::time::Duration;
 
+#[cfg(not(feature = "async"))]
 use socket2::{Domain, Socket, Type};
 
-mod dns;
-
-use dns::protocol;
-use dns::recursive;
+use montague::dns::protocol;
+use montague::dns::recursive;
+#[cfg(feature = "async")]
+use montague::dns::recursive::nonblocking;
 
 // Make Result<T> an alias for a result with a boxed error in it. This lets
 // us write methods that return multiple different types of errors more easily,
 // but has the drawback that we can't statically determine what is in the box.
-type Result<T> = std::result::Result<T, Box<dyn error::Error>>;
+// (It's Send so the async server can move it between threads.)
+type Result<T> = std::result::Result<T, Box<dyn error::Error + Send + Sync>>;
+
+// A query we've received, and checked is one we can do something with
+enum Received {
+    // A question to resolve
+    Query(protocol::DnsPacket),
+    // Something we can reply to straight away, like a malformed query
+    Reply(protocol::DnsPacket),
+}
 
 // Main server thread entry point. Creates a response to a received query.
+#[cfg(not(feature = "async"))]
 fn resolve_query(buf: &[u8]) -> Result<protocol::DnsPacket> {
+    let packet = match receive_query(buf)? {
+        Received::Query(packet) => packet,
+        Received::Reply(reply) => return Ok(reply),
+    };
+
+    // Run a recursive query on our one question, or have an upstream resolver do it if we're
+    // forwarding. If we can't get an answer, the client gets a SERVFAIL rather than silence.
+    let question = &packet.questions[0];
+    let result = if !recursive::forwarding_enabled() {
+        recursive::resolve_question(question)
+    } else if packet.flags.rd_bit {
+        recursive::forward_question(question)
+    } else {
+        Ok(cache_only_response(&packet))
+    };
+    Ok(finish_response(&packet, result))
+}
+
+// The same, without blocking a thread while we wait on other servers
+#[cfg(feature = "async")]
+async fn resolve_query_async(buf: &[u8]) -> Result<protocol::DnsPacket> {
+    let packet = match receive_query(buf)? {
+        Received::Query(packet) => packet,
+        Received::Reply(reply) => return Ok(reply),
+    };
+
+    let question = &packet.questions[0];
+    let result = if !recursive::forwarding_enabled() {
+        nonblocking::resolve_question(question).await
+    } else if packet.flags.rd_bit {
+        nonblocking::forward_question(question).await
+    } else {
+        Ok(cache_only_response(&packet))
+    };
+    Ok(finish_response(&packet, result))
+}
+
+fn receive_query(buf: &[u8]) -> Result<Received> {
     // Process the DNS packet received and print out some data from it
     let packet = match protocol::DnsPacket::from_bytes(buf) {
         Ok(x) => Ok(x),
@@ -24,7 +78,7 @@ fn resolve_query(buf: &[u8]) -> Result<protocol::DnsPacket> {
             match e.get_error_response() {
                 Some(response) => {
                     println!("Returning response {:?}", response);
-                    return Ok(response);
+                    return Ok(Received::Reply(response));
                 }
                 None => {
                     println!("Not enough info to build a response, dropping connection");
@@ -48,18 +102,65 @@ fn resolve_query(buf: &[u8]) -> Result<protocol::DnsPacket> {
         );
         return Err("Dropping out, implement a better thing here".into());
     };
+    Ok(Received::Query(packet))
+}
 
-    // Run a recursive query on our one question
-    let mut results = recursive::resolve_question(&packet.questions[0])?;
+// Turn the result of resolving a query into the reply to send the client
+fn finish_response<E: fmt::Display>(
+    packet: &protocol::DnsPacket,
+    result: std::result::Result<protocol::DnsPacket, E>,
+) -> protocol::DnsPacket {
+    let mut results = match result {
+        Ok(results) => results,
+        Err(error) => {
+            println!("Resolution failed: {}", error);
+            servfail_response(packet)
+        }
+    };
     // Use the originating txid
     results.id = packet.id;
     // Set the RA bit TODO this should probably be owned by the resolver code
     results.flags.ra_bit = true;
 
-    Ok(results)
+    results
+}
+
+// A forwarder has no business iterating from the roots itself, so a query that didn't ask for
+// recursion only gets what's already in the cache. Anything else is REFUSED.
+fn cache_only_response(query: &protocol::DnsPacket) -> protocol::DnsPacket {
+    recursive::cached_answer(&query.questions[0])
+        .unwrap_or_else(|| error_response(query, protocol::DnsRCode::Refused))
+}
+
+// Build a SERVFAIL reply to a query we couldn't resolve
+fn servfail_response(query: &protocol::DnsPacket) -> protocol::DnsPacket {
+    error_response(query, protocol::DnsRCode::ServFail)
+}
+
+// Build an empty reply to a query, with the given error code
+fn error_response(query: &protocol::DnsPacket, rcode: protocol::DnsRCode) -> protocol::DnsPacket {
+    let flags = protocol::DnsFlags {
+        qr_bit: true,
+        aa_bit: false,
+        tc_bit: false,
+        ra_bit: false,
+        ad_bit: false,
+        rcode,
+        // Copy the remaining flags given to us by the client
+        ..query.flags.to_owned()
+    };
+    protocol::DnsPacket {
+        id: query.id,
+        flags,
+        questions: query.questions.to_owned(),
+        answers: Vec::new(),
+        nameservers: Vec::new(),
+        addl_recs: Vec::new(),
+    }
 }
 
 // Listen on localhost (127.0.0.1) UDP port 5300 and reads up to 1500 bytes
+#[cfg(not(feature = "async"))]
 fn receive(socket: &net::UdpSocket) -> Result<([u8; 1500], usize, std::net::SocketAddr)> {
     // Receive data from the user.
     // TODO(dylan): Up to an MTU of 1500, consider using an alloc here
@@ -70,6 +171,7 @@ fn receive(socket: &net::UdpSocket) -> Result<([u8; 1500], usize, std::net::Sock
     Ok((buf, amt, src))
 }
 
+#[cfg(not(feature = "async"))]
 fn respond(
     socket: &net::UdpSocket,
     packet: &protocol::DnsPacket,
@@ -78,11 +180,114 @@ fn respond(
     // Send the results back to the client
     println!("Returning results: {:?}", packet);
     let response_bytes = &packet.to_bytes();
-    socket.send_to(&response_bytes, dest)?;
+    socket.send_to(response_bytes, dest)?;
+    Ok(())
+}
+
+// Build the resolver configuration from our command line flags. Anything not given keeps its
+// default value.
+//   --query-timeout-ms <ms>      How long to wait for an authority's reply before retransmitting
+//   --query-attempts <n>         How many times to send a query before moving on to another server
+//   --max-query-timeout-ms <ms>  Cap on the wait between retransmissions as it backs off
+//   --randomize-case <bool>      Whether to randomise query name case (DNS 0x20), on by default
+//   --root-hints <path>          Load root servers from a named.root file instead of the builtin list
+//   --root-selection <strategy>  How to pick a root server: "random" (the default) or "rtt"
+//   --ip-preference <family>     Talk to authorities over "v4", "v6", or "both" (the default)
+//   --edns <bool>                Whether to send EDNS0 OPT records to authorities, on by default
+//   --edns-payload-size <bytes>  The UDP payload size to advertise with EDNS0 (default 1232)
+//   --dnssec-ok <bool>           Whether to set the DO bit asking authorities for DNSSEC records
+//   --qname-minimisation <mode>  "off", "relaxed" (the default), or "strict"
+//   --stats-interval-secs <s>    Log metrics and per-authority RTT statistics this often
+//   --forward <address>          Forward recursive queries to this upstream resolver (an IP, with
+//                                an optional port) instead of resolving them; can be repeated
+//   --forward-strategy <s>       Which upstream to ask first: "round-robin" (the default),
+//                                "lowest-latency", or "strict-order"
+//   --forward-zone <zone>=<addresses>
+//                                Forward queries for names in a zone to these resolvers (a comma
+//                                separated list, like --forward takes); can be repeated
+//   --stub-zone <zone>=<addresses>
+//                                Start iterating at these authorities for names in a zone; can be
+//                                repeated
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<recursive::ResolverConfig> {
+    let mut config = recursive::ResolverConfig::default();
+    while let Some(flag) = args.next() {
+        let value = match args.next() {
+            Some(value) => value,
+            None => return Err(format!("Missing value for {}", flag).into()),
+        };
+        match flag.as_str() {
+            "--query-timeout-ms" => {
+                config.query.timeout = Duration::from_millis(value.parse()?);
+            }
+            "--query-attempts" => {
+                config.query.attempts = value.parse()?;
+            }
+            "--max-query-timeout-ms" => {
+                config.query.max_timeout = Duration::from_millis(value.parse()?);
+            }
+            "--randomize-case" => {
+                config.query.randomize_case = value.parse()?;
+            }
+            "--root-hints" => {
+                config.root_hints = Some(value.into());
+            }
+            "--root-selection" => {
+                config.root_selection = value.parse()?;
+            }
+            "--ip-preference" => {
+                config.ip_preference = value.parse()?;
+            }
+            "--edns" => {
+                config.query.edns = value.parse()?;
+            }
+            "--edns-payload-size" => {
+                config.query.edns_payload_size = value.parse()?;
+            }
+            "--dnssec-ok" => {
+                config.query.dnssec_ok = value.parse()?;
+            }
+            "--qname-minimisation" => {
+                config.qname_minimisation = value.parse()?;
+            }
+            "--stats-interval-secs" => {
+                config.stats_interval = Some(Duration::from_secs(value.parse()?));
+            }
+            "--forward" => {
+                config
+                    .forwarders
+                    .push(recursive::parse_server_address(&value)?);
+            }
+            "--forward-strategy" => {
+                config.forward_strategy = value.parse()?;
+            }
+            "--forward-zone" => {
+                config.forward_zones.push(value.parse()?);
+            }
+            "--stub-zone" => {
+                config.stub_zones.push(value.parse()?);
+            }
+            _ => return Err(format!("Unknown flag {}", flag).into()),
+        }
+    }
+    Ok(config)
+}
+
+// Set up the resolver from the command line, and start its background jobs
+fn start() -> Result<()> {
+    recursive::configure(parse_args(env::args().skip(1))?)?;
+    // When we're forwarding, the roots are probably out of reach anyway
+    if !recursive::forwarding_enabled() {
+        recursive::start_priming();
+    }
+    recursive::start_health_checks();
+    recursive::start_stats_reporting();
     Ok(())
 }
 
+#[cfg(not(feature = "async"))]
 fn main() -> Result<()> {
+    start()?;
+
     loop {
         // Open a socket for this listener
         let socket = Socket::new(Domain::ipv4(), Type::dgram(), None)?;
@@ -104,3 +309,32 @@ fn main() -> Result<()> {
         });
     }
 }
+
+// With the async feature, one socket takes every query, and each one is resolved in a task of its
+// own on the runtime rather than a thread
+#[cfg(feature = "async")]
+#[tokio::main]
+async fn main() -> Result<()> {
+    start()?;
+
+    let socket = std::sync::Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:5300").await?);
+    loop {
+        let mut buf = [0; 1500];
+        let (amt, client) = socket.recv_from(&mut buf).await?;
+        println!("Data received: {} bytes", amt);
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            let response = match resolve_query_async(&buf[0..amt]).await {
+                Ok(response) => response,
+                Err(error) => {
+                    println!("Error processing response! {:?}", error);
+                    return;
+                }
+            };
+            println!("Returning results: {:?}", response);
+            if let Err(error) = socket.send_to(&response.to_bytes(), client).await {
+                println!("Couldn't send response to {}: {}", client, error);
+            }
+        });
+    }
+}